@@ -0,0 +1,261 @@
+//! Deserialization of Rust's types from Lua's values with serde.
+
+use std::error;
+use std::fmt;
+
+use lua::{State, Index, Type};
+use serde::de::{self, Deserialize, DeserializeSeed, EnumAccess, IntoDeserializer,
+                MapAccess, SeqAccess, VariantAccess, Visitor};
+
+/// Error of deserialization.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+/// Reads value at `index` to `T`. The stack is left as it was.
+pub fn from_lua<'de, T: Deserialize<'de>>(state: &mut State, index: Index) -> Result<T, Error> {
+    let top = state.get_top();
+    let result = T::deserialize(&mut Deserializer::new(state, index));
+    state.set_top(top); // Iteration breaks on error and leaves key and value
+    result
+}
+
+/// Deserializer which reads value at index of the stack.
+///
+/// Non-empty sequences are read as sequences by `deserialize_any`, other tables are
+/// read as maps. Types which expect sequences or maps read tables as expected.
+/// Enums are strings for unit variants or tables with single field `variant = value`.
+pub struct Deserializer<'a> {
+    state: &'a mut State,
+    index: Index,
+}
+
+impl<'a> Deserializer<'a> {
+    pub fn new(state: &'a mut State, index: Index) -> Self {
+        let index = state.abs_index(index);
+        Deserializer {
+            state: state,
+            index: index,
+        }
+    }
+
+    fn unexpected(&mut self, expected: &str) -> Error {
+        match self.state.type_of(self.index) {
+            Some(t) => Error(format!("{} expected, got {:?}", expected, t)),
+            None => Error(format!("{} expected, got none", expected)),
+        }
+    }
+
+    fn check_table(&mut self) -> Result<(), Error> {
+        if self.state.is_table(self.index) {
+            Ok(())
+        } else {
+            Err(self.unexpected("table"))
+        }
+    }
+}
+
+impl<'de, 'a, 'b> de::Deserializer<'de> for &'b mut Deserializer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let index = self.index;
+        match self.state.type_of(index) {
+            None | Some(Type::None) | Some(Type::Nil) => visitor.visit_unit(),
+            Some(Type::Boolean) => visitor.visit_bool(self.state.to_bool(index)),
+            Some(Type::Number) => {
+                if self.state.is_integer(index) {
+                    visitor.visit_i64(self.state.to_integer(index))
+                } else {
+                    visitor.visit_f64(self.state.to_number(index))
+                }
+            },
+            Some(Type::String) => {
+                match self.state.to_str(index).map(str::to_owned) {
+                    Some(s) => visitor.visit_string(s),
+                    None => Err(self.unexpected("string")),
+                }
+            },
+            Some(Type::Table) => {
+                if self.state.raw_len(index) > 0 {
+                    self.deserialize_seq(visitor)
+                } else {
+                    self.deserialize_map(visitor)
+                }
+            },
+            Some(_) => Err(self.unexpected("serializable value")),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.state.is_none_or_nil(self.index) {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V)
+        -> Result<V::Value, Error>
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.check_table()?;
+        let len = self.state.raw_len(self.index);
+        visitor.visit_seq(Seq {
+            de: self,
+            position: 0,
+            len: len,
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize, visitor: V)
+        -> Result<V::Value, Error>
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.check_table()?;
+        self.state.push_nil();
+        visitor.visit_map(Map { de: self })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, _fields: &'static [&'static str], visitor: V)
+        -> Result<V::Value, Error>
+    {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V)
+        -> Result<V::Value, Error>
+    {
+        let index = self.index;
+        match self.state.type_of(index) {
+            Some(Type::String) => {
+                // Unit variant
+                match self.state.to_str(index).map(str::to_owned) {
+                    Some(s) => visitor.visit_enum(s.into_deserializer()),
+                    None => Err(self.unexpected("string")),
+                }
+            },
+            Some(Type::Table) => {
+                self.state.push_nil();
+                if !self.state.next(index) {
+                    return Err(Error("variant expected, got empty table".to_owned()));
+                }
+                let value = visitor.visit_enum(Enum { de: &mut *self })?;
+                self.state.pop(2); // Pop key and value of variant
+                Ok(value)
+            },
+            _ => Err(self.unexpected("variant")),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string
+        bytes byte_buf unit unit_struct identifier ignored_any
+    }
+}
+
+struct Seq<'b, 'a: 'b> {
+    de: &'b mut Deserializer<'a>,
+    position: usize,
+    len: usize,
+}
+
+impl<'de, 'b, 'a> SeqAccess<'de> for Seq<'b, 'a> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        if self.position >= self.len {
+            return Ok(None);
+        }
+        self.position += 1;
+        self.de.state.geti(self.de.index, self.position as i64);
+        let value = seed.deserialize(&mut Deserializer::new(&mut *self.de.state, -1))?;
+        self.de.state.pop(1);
+        Ok(Some(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len - self.position)
+    }
+}
+
+struct Map<'b, 'a: 'b> {
+    de: &'b mut Deserializer<'a>,
+}
+
+impl<'de, 'b, 'a> MapAccess<'de> for Map<'b, 'a> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        if !self.de.state.next(self.de.index) {
+            return Ok(None);
+        }
+        // Keys are never converted in place by `to_str`, that keeps `next` working
+        seed.deserialize(&mut Deserializer::new(&mut *self.de.state, -2)).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = seed.deserialize(&mut Deserializer::new(&mut *self.de.state, -1))?;
+        self.de.state.pop(1); // Pop `value` only
+        Ok(value)
+    }
+}
+
+struct Enum<'b, 'a: 'b> {
+    de: &'b mut Deserializer<'a>,
+}
+
+impl<'de, 'b, 'a> EnumAccess<'de> for Enum<'b, 'a> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let variant = seed.deserialize(&mut Deserializer::new(&mut *self.de.state, -2))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'b, 'a> VariantAccess<'de> for Enum<'b, 'a> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(&mut Deserializer::new(&mut *self.de.state, -1))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(&mut Deserializer::new(&mut *self.de.state, -1), visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V)
+        -> Result<V::Value, Error>
+    {
+        de::Deserializer::deserialize_map(&mut Deserializer::new(&mut *self.de.state, -1), visitor)
+    }
+}
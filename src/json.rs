@@ -0,0 +1,187 @@
+//! Conversion between Lua's values and JSON.
+
+use std::error::Error;
+use std::fmt;
+
+use lua::{State, Index, Integer, Type};
+use lua::libc::c_void;
+use serde_json::{Map, Number, Value};
+
+/// Error of conversion of Lua's value to JSON.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SerializeError {
+    /// Table contains itself.
+    Cycle,
+    /// Value of this type has no JSON representation.
+    UnsupportedType(Type),
+    /// Key of table is not a string or an integer.
+    InvalidKey(Type),
+    /// Number is NaN or infinite.
+    InvalidNumber,
+}
+
+impl fmt::Display for SerializeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SerializeError::Cycle => write!(f, "table contains itself"),
+            SerializeError::UnsupportedType(ref t) => write!(f, "unsupported value of type {:?}", t),
+            SerializeError::InvalidKey(ref t) => write!(f, "unsupported key of type {:?}", t),
+            SerializeError::InvalidNumber => write!(f, "number is not finite"),
+        }
+    }
+}
+
+impl Error for SerializeError {}
+
+enum Key {
+    Int(Integer),
+    Str(String),
+}
+
+/// Reads value at `index` to JSON value.
+///
+/// Table is an array if its keys are integers `1..n`, otherwise it's an object.
+/// Empty tables are objects.
+pub fn to_json_value(state: &mut State, index: Index) -> Result<Value, SerializeError> {
+    let mut visited = Vec::new();
+    read_value(state, index, &mut visited)
+}
+
+/// Reads all arguments of function (the whole stack) to JSON values.
+///
+/// Error contains position of the argument which can't be converted.
+pub fn args_to_json(state: &mut State) -> Result<Vec<Value>, (Index, SerializeError)> {
+    let top = state.get_top();
+    let mut args = Vec::with_capacity(top as usize);
+    for position in 1..top + 1 {
+        let value = to_json_value(state, position).map_err(|err| (position, err))?;
+        args.push(value);
+    }
+    Ok(args)
+}
+
+fn read_value(state: &mut State, index: Index, visited: &mut Vec<*const c_void>) -> Result<Value, SerializeError> {
+    let index = state.abs_index(index);
+    match state.type_of(index) {
+        None | Some(Type::None) | Some(Type::Nil) => Ok(Value::Null),
+        Some(Type::Boolean) => Ok(Value::Bool(state.to_bool(index))),
+        Some(Type::Number) => {
+            if state.is_integer(index) {
+                Ok(Value::from(state.to_integer(index)))
+            } else {
+                Number::from_f64(state.to_number(index))
+                    .map(Value::Number)
+                    .ok_or(SerializeError::InvalidNumber)
+            }
+        },
+        Some(Type::String) => {
+            state.to_str(index)
+                .map(|s| Value::String(s.to_owned()))
+                .ok_or(SerializeError::UnsupportedType(Type::String))
+        },
+        Some(Type::Table) => {
+            let pointer = state.to_pointer(index);
+            if visited.contains(&pointer) {
+                return Err(SerializeError::Cycle);
+            }
+            visited.push(pointer);
+            let top = state.get_top();
+            let result = read_table(state, index, visited);
+            state.set_top(top); // Iteration breaks on error and leaves key and value
+            visited.pop();
+            result
+        },
+        Some(other) => Err(SerializeError::UnsupportedType(other)),
+    }
+}
+
+fn read_table(state: &mut State, index: Index, visited: &mut Vec<*const c_void>) -> Result<Value, SerializeError> {
+    let mut entries = Vec::new();
+    state.push_nil();
+    while state.next(index) {
+        // Don't use `to_str` for numbers, because it changes the key and breaks `next`
+        let key = match state.type_of(-2) {
+            Some(Type::Number) if state.is_integer(-2) => Key::Int(state.to_integer(-2)),
+            Some(Type::String) => {
+                let key = state.to_str(-2).map(str::to_owned);
+                Key::Str(key.ok_or(SerializeError::InvalidKey(Type::String))?)
+            },
+            Some(other) => return Err(SerializeError::InvalidKey(other)),
+            None => return Err(SerializeError::InvalidKey(Type::None)),
+        };
+        let value = read_value(state, -1, visited)?;
+        entries.push((key, value));
+        state.pop(1); // Pop `value` only
+    }
+    let mut ints: Vec<Integer> = entries.iter()
+        .filter_map(|&(ref key, _)| match *key {
+            Key::Int(i) => Some(i),
+            Key::Str(_) => None,
+        })
+        .collect();
+    ints.sort();
+    let is_array = !entries.is_empty()
+        && ints.len() == entries.len()
+        && ints.iter().enumerate().all(|(n, i)| *i == n as Integer + 1);
+    if is_array {
+        let mut items: Vec<(Integer, Value)> = entries.into_iter()
+            .map(|(key, value)| match key {
+                Key::Int(i) => (i, value),
+                Key::Str(_) => unreachable!(),
+            })
+            .collect();
+        items.sort_by_key(|&(i, _)| i);
+        Ok(Value::Array(items.into_iter().map(|(_, value)| value).collect()))
+    } else {
+        let mut map = Map::new();
+        for (key, value) in entries {
+            let key = match key {
+                Key::Int(i) => i.to_string(),
+                Key::Str(s) => s,
+            };
+            map.insert(key, value);
+        }
+        Ok(Value::Object(map))
+    }
+}
+
+/// Pushes JSON value to the stack.
+///
+/// Objects become tables with string keys, arrays become sequences and `null` becomes `nil`.
+pub fn push_json_value(state: &mut State, value: &Value) {
+    match *value {
+        Value::Null => state.push_nil(),
+        Value::Bool(b) => state.push_bool(b),
+        Value::Number(ref n) => {
+            if let Some(i) = n.as_i64() {
+                state.push_integer(i);
+            } else {
+                state.push_number(n.as_f64().unwrap_or(0.0));
+            }
+        },
+        Value::String(ref s) => {
+            state.push_string(s);
+        },
+        Value::Array(ref items) => {
+            state.create_table(items.len() as i32, 0);
+            for (idx, item) in items.iter().enumerate() {
+                push_json_value(state, item);
+                state.raw_seti(-2, idx as Integer + 1);
+            }
+        },
+        Value::Object(ref map) => {
+            state.create_table(0, map.len() as i32);
+            for (key, item) in map {
+                push_json_value(state, item);
+                state.set_field(-2, key);
+            }
+        },
+    }
+}
+
+/// Parses JSON text and pushes its value to the stack.
+pub fn push_json(state: &mut State, text: &str) -> Result<(), ::serde_json::Error> {
+    let value = ::serde_json::from_str(text)?;
+    push_json_value(state, &value);
+    Ok(())
+}
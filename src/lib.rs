@@ -155,134 +155,7784 @@
 //!     assert!(wrong.is_none());
 //! }
 //! ```
+//!
+//! ## Read string tags to enums
+//!
+//! Macro `lua_str_enum` reuses `FromStr` and `Display` implementations of a type
+//! to read it from Lua's string and push it back:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use std::fmt;
+//! # use std::str::FromStr;
+//!
+//! #[derive(Debug, PartialEq)]
+//! enum Mode {
+//!     Read,
+//!     Write,
+//! }
+//!
+//! impl FromStr for Mode {
+//!     type Err = ();
+//!
+//!     fn from_str(s: &str) -> Result<Self, ()> {
+//!         match s {
+//!             "read" => Ok(Mode::Read),
+//!             "write" => Ok(Mode::Write),
+//!             _ => Err(()),
+//!         }
+//!     }
+//! }
+//!
+//! impl fmt::Display for Mode {
+//!     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+//!         match *self {
+//!             Mode::Read => write!(f, "read"),
+//!             Mode::Write => write!(f, "write"),
+//!         }
+//!     }
+//! }
+//!
+//! lua_str_enum!(Mode);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return 'read'");
+//!     assert_eq!(state.to_type::<Mode>(-1), Some(Mode::Read));
+//!
+//!     state.do_string("return 'append'");
+//!     assert_eq!(state.to_type::<Mode>(-1), None);
+//!
+//!     state.push(Mode::Write);
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "write");
+//!     assert_eq!(state.to_type::<Mode>(-1), Some(Mode::Write));
+//! }
+//! ```
+//!
+//! ## Declare userdata with methods and operators
+//!
+//! Macro `lua_type` attaches methods table (used as `__index`) and metamethods
+//! to userdata type in one declaration:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Number};
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! #[derive(Clone, Debug, PartialEq)]
+//! struct Vec2 {
+//!     x: Number,
+//!     y: Number,
+//! }
+//!
+//! unsafe extern "C" fn vec2_dot(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let (a, b) = convert_arguments!(state, Vec2, Vec2).unwrap();
+//!     state.push(a.x * b.x + a.y * b.y);
+//!     1
+//! }
+//!
+//! unsafe extern "C" fn vec2_add(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let (a, b) = convert_arguments!(state, Vec2, Vec2).unwrap();
+//!     state.push(Vec2 { x: a.x + b.x, y: a.y + b.y });
+//!     1
+//! }
+//!
+//! unsafe extern "C" fn vec2_tostring(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let (a,) = convert_arguments!(state, Vec2).unwrap();
+//!     state.push_string(&format!("({}, {})", a.x, a.y));
+//!     1
+//! }
+//!
+//! lua_type!(Vec2 {
+//!     methods: { dot => vec2_dot },
+//!     meta: { "__add" => vec2_add, "__tostring" => vec2_tostring },
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     Vec2::attach(&mut state);
+//!
+//!     state.push(Vec2 { x: 1.0, y: 2.0 });
+//!     state.set_global("a");
+//!     state.push(Vec2 { x: 3.0, y: 4.0 });
+//!     state.set_global("b");
+//!
+//!     state.do_string("return a:dot(b)");
+//!     assert_eq!(state.to_type::<Number>(-1), Some(11.0));
+//!
+//!     state.do_string("return a + b");
+//!     assert_eq!(state.to_type::<Vec2>(-1), Some(Vec2 { x: 4.0, y: 6.0 }));
+//! }
+//! ```
+//!
+//! ## Read tables in arguments directly
+//!
+//! Slot `map<K, V>` of `convert_arguments` reads a table argument to `HashMap`
+//! without declaring a wrapper type:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! pub unsafe extern "C" fn sum_by_key(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let (factor, map) = convert_arguments!(state, Integer, map<String, Integer>)
+//!         .map_err(|pos| {
+//!             state.arg_error(pos, "integer and table of integers expected");
+//!         }).unwrap();
+//!     state.push(factor * (map["a"] + map["b"]));
+//!     1
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(sum_by_key));
+//!     state.set_global("sum_by_key");
+//!
+//!     assert!(!state.do_string("return sum_by_key(10, {a = 1, b = 2})").is_err());
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(30));
+//!
+//!     assert!(state.do_string("return sum_by_key(10, {a = 'x'})").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #2"));
+//! }
+//! ```
+//!
+//! ## Expose enums as constants tables
+//!
+//! Macro `lua_enum` maps variants to Lua values and registers a global table with
+//! constants. Flag `call` makes the table a validating constructor:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! #[derive(Debug, PartialEq)]
+//! enum Color {
+//!     Red,
+//!     Green,
+//!     Blue,
+//! }
+//!
+//! lua_enum!(Color { Red => 1, Green => 2, Blue => 3 }, call);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     Color::attach(&mut state);
+//!
+//!     state.do_string("return Color.Red");
+//!     assert_eq!(state.to_type::<Color>(-1), Some(Color::Red));
+//!
+//!     state.push(Color::Blue);
+//!     assert_eq!(state.to_type::<i64>(-1), Some(3));
+//!
+//!     assert!(!state.do_string("return Color(2)").is_err());
+//!     assert_eq!(state.to_type::<Color>(-1), Some(Color::Green));
+//!
+//!     assert!(state.do_string("return Color(99)").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("invalid value for `Color`"));
+//! }
+//! ```
+//!
+//! ## Pop values with underflow check
+//!
+//! Macro `safe_pop` pops values only if stack has enough of them:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push(1);
+//!     state.push(2);
+//!     state.push(3);
+//!
+//!     assert!(safe_pop!(state, 2).is_ok());
+//!     assert_eq!(state.get_top(), 1);
+//!
+//!     let err = safe_pop!(state, 2).unwrap_err();
+//!     assert_eq!(err, "can't pop 2 values from stack of 1");
+//!     assert_eq!(state.get_top(), 1);
+//! }
+//! ```
+//!
+//! ## Read arrays of tables
+//!
+//! Elements of `lua_array_type` could be any type which implements `FromLua`,
+//! even structs of `lua_struct`. Array ends at the first `nil`, an empty table
+//! is an element which is rejected by element's conversion:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_struct!(#[derive(Debug, PartialEq)] Point { x: Integer, y: Integer });
+//!
+//! lua_array_type!(Points<Point>);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return {{x = 1, y = 2}, {x = 3, y = 4}}");
+//!     let Points(points) = state.to_type(-1).unwrap();
+//!     assert_eq!(points, vec![Point { x: 1, y: 2 }, Point { x: 3, y: 4 }]);
+//!
+//!     state.do_string("return {{x = 1, y = 2}, {}}");
+//!     assert!(state.to_type::<Points>(-1).is_none());
+//!
+//!     state.do_string("return {{x = 1, y = 2}, nil, {x = 3, y = 4}}");
+//!     let Points(points) = state.to_type(-1).unwrap();
+//!     assert_eq!(points, vec![Point { x: 1, y: 2 }]);
+//! }
+//! ```
+//!
+//! ## Register native modules for `require`
+//!
+//! Macro `register_loader` puts a loader to `package.preload`, so scripts load
+//! the module lazily with `require`:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! unsafe extern "C" fn answer(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     state.push(42);
+//!     1
+//! }
+//!
+//! unsafe extern "C" fn open_mymod(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     state.new_table();
+//!     state.push_fn(Some(answer));
+//!     state.set_field(-2, "answer");
+//!     1
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     register_loader!(state, "mymod", open_mymod);
+//!
+//!     assert!(!state.do_string("return require('mymod').answer()").is_err());
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(42));
+//! }
+//! ```
+//!
+//! ## Convert 1-based indices
+//!
+//! Slot `index1` of `convert_arguments` checks that an argument is a positive
+//! integer and converts it to 0-based `usize`:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! pub unsafe extern "C" fn zero_based(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let (idx,) = convert_arguments!(state, index1)
+//!         .map_err(|pos| {
+//!             state.arg_error(pos, "positive index expected");
+//!         }).unwrap();
+//!     state.push(idx as i64);
+//!     1
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(zero_based));
+//!     state.set_global("f");
+//!
+//!     state.do_string("return f(1)");
+//!     assert_eq!(state.to_type::<i64>(-1), Some(0));
+//!
+//!     state.do_string("return f(3)");
+//!     assert_eq!(state.to_type::<i64>(-1), Some(2));
+//!
+//!     assert!(state.do_string("return f(0)").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #1"));
+//! }
+//! ```
+//!
+//! ## Build tables step by step
+//!
+//! Macro `table_builder` creates `TableBuilder` to fill a table when fields are
+//! known only at runtime:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     for &admin in &[true, false] {
+//!         let mut builder = table_builder!(state)
+//!             .field("name", "bob")
+//!             .item(10)
+//!             .item(20);
+//!         if admin {
+//!             builder = builder.field("admin", true);
+//!         }
+//!         builder.build();
+//!         state.set_global("user");
+//!
+//!         state.do_string("return user.name, user[1] + user[2], user.admin");
+//!         assert_eq!(state.to_type::<String>(-3).unwrap(), "bob");
+//!         assert_eq!(state.to_type::<Integer>(-2), Some(30));
+//!         assert_eq!(state.is_nil(-1), !admin);
+//!     }
+//! }
+//! ```
+//!
+//! ## Read loosely-typed values
+//!
+//! Macro `to_any` tries types in order and returns `OneOf2`, `OneOf3` or
+//! `OneOf4` enum with the first successful conversion. These enums implement
+//! `FromLua`, so they could be used as slots of `convert_arguments` too:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer, Number};
+//! # use lua_macros::OneOf3;
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return 10");
+//!     assert_eq!(to_any!(state, -1, [Integer, Number, String]), Some(OneOf3::A(10)));
+//!
+//!     state.do_string("return 2.5");
+//!     assert_eq!(to_any!(state, -1, [Integer, Number, String]), Some(OneOf3::B(2.5)));
+//!
+//!     state.do_string("return 'ten'");
+//!     assert_eq!(to_any!(state, -1, [Integer, Number, String]), Some(OneOf3::C("ten".to_owned())));
+//!
+//!     state.do_string("return {}");
+//!     assert_eq!(to_any!(state, -1, [Integer, Number, String]), None);
+//! }
+//! ```
+//!
+//! ## Keep per-instance data of userdata
+//!
+//! Flag `uservalue` of `lua_userdata` gives every pushed instance its own table.
+//! Scripts read and write fields of it as fields of userdata. Missing fields are
+//! taken from methods or `__index` function, and `__newindex` function takes writes:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! #[derive(Clone)]
+//! struct Node;
+//!
+//! lua_userdata!(Node; uservalue);
+//!
+//! #[derive(Clone)]
+//! struct Named;
+//!
+//! unsafe extern "C" fn name_of(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     state.push_string("default");
+//!     1
+//! }
+//!
+//! lua_userdata!(Named, "__index" => name_of; uservalue);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     Node::attach(&mut state);
+//!
+//!     state.push(Node);
+//!     state.set_global("node");
+//!     state.do_string("node.label = 'root'");
+//!
+//!     state.get_global("node");
+//!     Node::uservalue(&mut state, -1);
+//!     state.get_field(-1, "label");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "root");
+//!
+//!     state.do_string("return node.label, node.missing");
+//!     assert_eq!(state.to_type::<String>(-2).unwrap(), "root");
+//!     assert!(state.is_nil(-1));
+//!
+//!     Named::attach(&mut state);
+//!     state.push(Named);
+//!     state.set_global("named");
+//!     state.do_string("named.label = 'own' return named.label, named.missing");
+//!     assert_eq!(state.to_type::<String>(-2).unwrap(), "own");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "default");
+//! }
+//! ```
+//!
+//! ## Read tables to maps with custom hasher
+//!
+//! Pass hasher as the third type parameter of `lua_table_type`:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//! # use std::collections::hash_map::DefaultHasher;
+//! # use std::hash::BuildHasherDefault;
+//!
+//! type FixedState = BuildHasherDefault<DefaultHasher>;
+//!
+//! lua_table_type!(FixedTable<String, Integer, FixedState>);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.do_string("return {one = 1, two = 2}");
+//!     let FixedTable(table) = state.to_type(-1).unwrap();
+//!     assert_eq!(table.len(), 2);
+//!     assert_eq!(table.get("one"), Some(&1));
+//!     assert_eq!(table.get("two"), Some(&2));
+//! }
+//! ```
+//!
+//! ## Return snapshots of collections
+//!
+//! Macro `return_iter_snapshot` copies items of an iterator to a new array and
+//! returns count of results for Lua function:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! unsafe extern "C" fn squares(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let items: Vec<Integer> = vec![1, 2, 3];
+//!     return_iter_snapshot!(state, items.iter().map(|x| x * x))
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     state.push_fn(Some(squares));
+//!     state.set_global("squares");
+//!
+//!     state.do_string("local s = 0 for _, v in ipairs(squares()) do s = s + v end return s");
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(14));
+//! }
+//! ```
+//!
+//! ## Compute globals on first access
+//!
+//! Macro `lazy_global` defines a global which value is produced by a closure
+//! on the first access and cached after that. It sets `__index` of metatable
+//! of the globals table and passes other keys to the previous handler:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//! # use std::cell::Cell;
+//! # use std::rc::Rc;
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     state.do_string("setmetatable(_G, { __index = function(_, key) error('unknown ' .. key) end })");
+//!
+//!     let calls = Rc::new(Cell::new(0));
+//!     let counter = calls.clone();
+//!     lazy_global!(state, "answer", move || {
+//!         counter.set(counter.get() + 1);
+//!         42
+//!     });
+//!     assert_eq!(calls.get(), 0);
+//!
+//!     state.do_string("return answer");
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(42));
+//!
+//!     state.do_string("return answer + 1");
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(43));
+//!
+//!     assert_eq!(calls.get(), 1);
+//!
+//!     lazy_global!(state, "other", || 1);
+//!     state.do_string("return other + answer");
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(43));
+//!     assert!(state.do_string("return missing").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("unknown missing"));
+//! }
+//! ```
+//!
+//! ## Accept one of string options
+//!
+//! Slot `one_of[...]` of `convert_arguments` checks a string argument against
+//! allowed values:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! pub unsafe extern "C" fn open(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let (path, mode) = convert_arguments!(state, String, one_of["read", "write", "append"])
+//!         .map_err(|pos| {
+//!             state.arg_error(pos, "path and mode expected");
+//!         }).unwrap();
+//!     state.push_string(&format!("{}:{}", path, mode));
+//!     1
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(open));
+//!     state.set_global("open");
+//!
+//!     assert!(!state.do_string("return open('file.txt', 'write')").is_err());
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "file.txt:write");
+//!
+//!     assert!(state.do_string("return open('file.txt', 'delete')").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #2"));
+//! }
+//! ```
+//!
+//! ## Add tracebacks to errors
+//!
+//! Macro `do_string_traced` works like `State::do_string`, but uses
+//! `debug.traceback` as message handler, so an error contains the stack traceback:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!
+//!     assert!(!do_string_traced!(state, "return 1").is_err());
+//!     assert_eq!(state.to_type::<i64>(-1), Some(1));
+//!
+//!     assert!(do_string_traced!(state, "local function f() error('oops') end f()").is_err());
+//!     let msg = state.to_type::<String>(-1).unwrap();
+//!     assert!(msg.contains("oops"));
+//!     assert!(msg.contains("stack traceback"));
+//! }
+//! ```
+//!
+//! ## Convert values to JSON
+//!
+//! With `json` feature macro `lua_to_json` converts any value to JSON text.
+//! Tables with keys `1..n` become arrays and other tables become objects:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # #[cfg(feature = "json")]
+//! # use lua_macros::json::SerializeError;
+//!
+//! # #[cfg(feature = "json")]
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return {name = 'bob', tags = {'a', 'b'}, age = 30}");
+//!     let json = lua_to_json!(state, -1).unwrap();
+//!     assert!(json.contains("\"tags\":[\"a\",\"b\"]"));
+//!     assert!(json.contains("\"age\":30"));
+//!
+//!     state.do_string("local t = {} t.self = t return t");
+//!     assert_eq!(lua_to_json!(state, -1), Err(SerializeError::Cycle));
+//! }
+//! # #[cfg(not(feature = "json"))]
+//! # fn main() { }
+//! ```
+//!
+//! ## Import JSON to Lua
+//!
+//! With `json` feature macro `json_to_lua` parses JSON text and pushes its value:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! # #[cfg(feature = "json")]
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     json_to_lua!(state, r#"{"name": "bob", "tags": ["a", "b"], "boss": null}"#).unwrap();
+//!     state.set_global("user");
+//!     state.do_string("return user.name, #user.tags, user.tags[2], user.boss");
+//!     assert_eq!(state.to_type::<String>(-4).unwrap(), "bob");
+//!     assert_eq!(state.to_type::<i64>(-3), Some(2));
+//!     assert_eq!(state.to_type::<String>(-2).unwrap(), "b");
+//!     assert!(state.is_nil(-1));
+//!
+//!     assert!(json_to_lua!(state, "{broken").is_err());
+//! }
+//! # #[cfg(not(feature = "json"))]
+//! # fn main() { }
+//! ```
+//!
+//! ## Trace access to fields of userdata
+//!
+//! Flag `trace_access` of `lua_userdata` reports reads and writes of fields in
+//! debug builds. Use `set_trace_sink` to collect messages instead of printing them
+//! to stderr:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use std::cell::RefCell;
+//! # use std::rc::Rc;
+//!
+//! #[derive(Clone)]
+//! struct Node;
+//!
+//! lua_userdata!(Node; uservalue, trace_access);
+//!
+//! fn main() {
+//!     let log = Rc::new(RefCell::new(Vec::new()));
+//!     let sink = log.clone();
+//!     lua_macros::set_trace_sink(move |msg| sink.borrow_mut().push(msg.to_owned()));
+//!
+//!     let mut state = State::new();
+//!     Node::attach(&mut state);
+//!     state.push(Node);
+//!     state.set_global("node");
+//!
+//!     state.do_string("node.label = 'root' return node.label");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "root");
+//!     if cfg!(debug_assertions) {
+//!         assert_eq!(*log.borrow(), vec!["Node newindex label", "Node index label"]);
+//!     }
+//! }
+//! ```
+//!
+//! ## Read percentages
+//!
+//! Slots `percent` and `percent(scale)` of `convert_arguments` check range of a
+//! number and return `Percent` with fraction:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//! # use lua_macros::Percent;
+//!
+//! pub unsafe extern "C" fn blend(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let (alpha, volume) = convert_arguments!(state, percent, percent(100))
+//!         .map_err(|pos| {
+//!             state.arg_error(pos, "percentage expected");
+//!         }).unwrap();
+//!     state.push(Percent(alpha.0 * volume.0));
+//!     1
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(blend));
+//!     state.set_global("blend");
+//!
+//!     assert!(!state.do_string("return blend(0.5, 50)").is_err());
+//!     assert_eq!(state.to_type::<Percent>(-1), Some(Percent(0.25)));
+//!
+//!     assert!(!state.do_string("return blend(1, 100)").is_err());
+//!     assert_eq!(state.to_type::<Percent>(-1), Some(Percent(1.0)));
+//!
+//!     assert!(state.do_string("return blend(0.5, 150)").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #2"));
+//!
+//!     assert_eq!(Percent::from_scaled(0.0, 0.0), None);
+//!     assert_eq!(Percent::from_scaled(-1.0, -2.0), None);
+//!
+//!     state.push("0.5");
+//!     assert_eq!(state.to_type::<Percent>(-1), None);
+//! }
+//! ```
+//!
+//! ## Describe call site
+//!
+//! Macro `register_debug_where` registers a global function which returns
+//! description of the place it was called from as `source:line: in function 'name'`:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     register_debug_where!(state, "debug_where");
+//!
+//!     // Avoid tail calls, because they lose information about the callers
+//!     state.do_string("local function inner()\n  local place = debug_where()\n  return place\nend\nlocal place = inner()\nreturn place");
+//!     let place = state.to_type::<String>(-1).unwrap();
+//!     assert!(place.contains(":2:"));
+//!     assert!(place.contains("in function 'inner'"));
+//! }
+//! ```
+//!
+//! ## Compare tables by contents
+//!
+//! Macro `lua_table_eq` compares tables recursively:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return {1, 2, {x = 'a'}}, {1, 2, {x = 'a'}}");
+//!     assert!(lua_table_eq!(state, -2, -1));
+//!
+//!     state.do_string("return {1, 2, {x = 'a'}}, {1, 2, {x = 'b'}}");
+//!     assert!(!lua_table_eq!(state, -2, -1));
+//!
+//!     state.do_string("return {1, 2}, {1, 2, 3}");
+//!     assert!(!lua_table_eq!(state, -2, -1));
+//!
+//!     state.do_string("local a, b = {}, {} a.me = a b.me = b return a, b");
+//!     assert!(lua_table_eq!(state, -2, -1));
+//! }
+//! ```
+//!
+//! ## Share maps with scripts
+//!
+//! Macro `lua_map_proxy` makes userdata type which wraps
+//! `Rc<RefCell<HashMap<String, V>>>`, so scripts and Rust see the same map:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_map_proxy!(Registry<Integer>);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     Registry::attach(&mut state);
+//!
+//!     let registry = Registry::new();
+//!     registry.0.borrow_mut().insert("a".to_owned(), 1);
+//!     state.push(registry.clone());
+//!     state.set_global("reg");
+//!
+//!     assert!(!state.do_string("reg.b = reg.a + 1").is_err());
+//!     assert_eq!(registry.0.borrow().get("b"), Some(&2));
+//!
+//!     registry.0.borrow_mut().insert("c".to_owned(), 5);
+//!     assert!(!state.do_string("local sum = 0 for k, v in pairs(reg) do sum = sum + v end return sum").is_err());
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(8));
+//!
+//!     assert!(!state.do_string("reg.a = nil").is_err());
+//!     assert!(!registry.0.borrow().contains_key("a"));
+//!
+//!     assert!(state.do_string("reg[1] = 2").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("string key expected"));
+//!
+//!     assert!(state.do_string("reg.d = {}").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("unsupported value"));
+//!     assert!(!registry.0.borrow().contains_key("d"));
+//! }
+//! ```
+//!
+//! ## Format variadic arguments
+//!
+//! Slot `format` of `convert_arguments` takes a format string with all remaining
+//! arguments and formats them with `string.format`. If formatting fails, the
+//! format string is the wrong argument:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! pub unsafe extern "C" fn log(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let (level, message) = convert_arguments!(state, String, format)
+//!         .map_err(|pos| {
+//!             state.arg_error(pos, "level and format expected");
+//!         }).unwrap();
+//!     state.push_string(&format!("[{}] {}", level, message));
+//!     1
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     state.push_fn(Some(log));
+//!     state.set_global("log");
+//!
+//!     assert!(!state.do_string("return log('info', '%d items', 3)").is_err());
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "[info] 3 items");
+//!
+//!     assert!(state.do_string("return log('info')").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #2"));
+//!
+//!     assert!(state.do_string("return log('info', '%d items', 'many')").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #2"));
+//!
+//!     // Without the `string` library
+//!     let mut bare = State::new();
+//!     bare.push_fn(Some(log));
+//!     bare.set_global("log");
+//!     assert!(bare.do_string("return log('info', 'items')").is_err());
+//!     assert!(bare.to_str(-1).unwrap().contains("bad argument #2"));
+//! }
+//! ```
+//!
+//! ## Read slices without copying
+//!
+//! Macro `push_struct_slice_view` pushes userdata which converts items of a slice
+//! only when scripts read them. It's unsafe, because the slice has to outlive the view:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer, ToLua};
+//!
+//! struct Player {
+//!     name: String,
+//!     hp: Integer,
+//! }
+//!
+//! impl ToLua for Player {
+//!     fn to_lua(&self, state: &mut State) {
+//!         state.new_table();
+//!         state.push_string(&self.name);
+//!         state.set_field(-2, "name");
+//!         state.push(self.hp);
+//!         state.set_field(-2, "hp");
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     let players = vec![
+//!         Player { name: "alice".to_owned(), hp: 10 },
+//!         Player { name: "bob".to_owned(), hp: 20 },
+//!     ];
+//!
+//!     unsafe {
+//!         push_struct_slice_view!(state, players);
+//!     }
+//!     state.set_global("players");
+//!
+//!     state.do_string("return players[2].name, players[1].hp, #players, players[3]");
+//!     assert_eq!(state.to_type::<String>(-4).unwrap(), "bob");
+//!     assert_eq!(state.to_type::<Integer>(-3), Some(10));
+//!     assert_eq!(state.to_type::<Integer>(-2), Some(2));
+//!     assert!(state.is_nil(-1));
+//!
+//!     assert!(state.do_string("players[1] = 5").is_err());
+//! }
+//! ```
+//!
+//! ## Declare functions with keyword arguments
+//!
+//! Macro `lua_kwargs_fn` declares a function which takes a single table and reads
+//! its fields. Fields with default values are optional, trailing `_` of a name is
+//! removed from the key:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_kwargs_fn!(fn spawn { type_: String, hp: Integer = 5 } -> String {
+//!     format!("{}:{}", type_, hp)
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(spawn));
+//!     state.set_global("spawn");
+//!
+//!     state.do_string("return spawn{type = 'orc', hp = 10}");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "orc:10");
+//!
+//!     state.do_string("return spawn{type = 'elf'}");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "elf:5");
+//!
+//!     assert!(state.do_string("return spawn{hp = 1}").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("missing field 'type'"));
+//!
+//!     assert!(state.do_string("return spawn{type = 'orc', hp = 'many'}").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("wrong type of field 'hp'"));
+//! }
+//! ```
+//!
+//! ## Match integer arguments
+//!
+//! Macro `match_int` reads an integer argument and evaluates the matching arm.
+//! Other values raise an argument error:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! pub unsafe extern "C" fn level(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let name = match_int!(state, 1, {
+//!         0 => "low",
+//!         1 => "medium",
+//!         2 => "high",
+//!     });
+//!     state.push_string(name);
+//!     1
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(level));
+//!     state.set_global("level");
+//!
+//!     for &(arg, name) in &[(0, "low"), (1, "medium"), (2, "high")] {
+//!         state.do_string(&format!("return level({})", arg));
+//!         assert_eq!(state.to_type::<String>(-1).unwrap(), name);
+//!     }
+//!
+//!     assert!(state.do_string("return level(3)").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #1"));
+//! }
+//! ```
+//!
+//! ## Parse string arguments
+//!
+//! Macro `parse_arg` parses a string argument with `FromStr` and raises an
+//! argument error with description of parsing error:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//! # use std::net::Ipv4Addr;
+//!
+//! pub unsafe extern "C" fn is_loopback(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let addr = parse_arg!(state, 1, Ipv4Addr);
+//!     state.push(addr.is_loopback());
+//!     1
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(is_loopback));
+//!     state.set_global("is_loopback");
+//!
+//!     assert!(!state.do_string("return is_loopback('127.0.0.1')").is_err());
+//!     assert_eq!(state.to_type::<bool>(-1), Some(true));
+//!
+//!     assert!(state.do_string("return is_loopback('localhost')").is_err());
+//!     let msg = state.to_type::<String>(-1).unwrap();
+//!     assert!(msg.contains("bad argument #1"));
+//!     assert!(msg.contains("invalid IPv4 address syntax"));
+//!
+//!     assert!(state.do_string("return is_loopback(127)").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("string expected"));
+//! }
+//! ```
+//!
+//! ## Associate data with Lua's objects
+//!
+//! Macro `weak_registry` creates a table with weak keys in the registry. Macros
+//! `assoc_set` and `assoc_get` attach values to objects, and these values are
+//! dropped when the objects are collected. Without the table they do nothing:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer, GcOption, REGISTRYINDEX};
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.do_string("obj = {}");
+//!     state.get_global("obj");
+//!     assert!(!assoc_set!(state, -1, 42));
+//!     assert_eq!(assoc_get!(state, -1, Integer), None);
+//!     state.pop(1);
+//!
+//!     weak_registry!(state);
+//!
+//!     state.get_global("obj");
+//!     assert!(assoc_set!(state, -1, 42));
+//!     assert_eq!(assoc_get!(state, -1, Integer), Some(42));
+//!     state.pop(1);
+//!
+//!     state.do_string("other = {}");
+//!     state.get_global("other");
+//!     assert_eq!(assoc_get!(state, -1, Integer), None);
+//!     state.pop(1);
+//!
+//!     state.do_string("obj = nil");
+//!     state.gc(GcOption::Collect, 0);
+//!     state.get_field(REGISTRYINDEX, "lua_macros.weak_registry");
+//!     state.push_nil();
+//!     assert!(!state.next(-2));
+//! }
+//! ```
+//!
+//! ## Read configs with environment overrides
+//!
+//! Macro `lua_config` declares a struct which reads fields from a table. Missing
+//! fields are taken from environment variables with `env(name, default)` or from
+//! default values:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//! # use std::env;
+//!
+//! lua_config!(AppConfig {
+//!     port: Integer = env("LUA_MACROS_EXAMPLE_PORT", 8080),
+//!     host: String = "localhost",
+//!     name: String,
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     env::set_var("LUA_MACROS_EXAMPLE_PORT", "9090");
+//!
+//!     state.do_string("return {port = 1000, host = 'example.com', name = 'app'}");
+//!     let config: AppConfig = state.to_type(-1).unwrap();
+//!     assert_eq!(config.port, 1000);
+//!     assert_eq!(config.host, "example.com");
+//!
+//!     state.do_string("return {name = 'app'}");
+//!     let config: AppConfig = state.to_type(-1).unwrap();
+//!     assert_eq!(config.port, 9090);
+//!     assert_eq!(config.host, "localhost");
+//!
+//!     env::remove_var("LUA_MACROS_EXAMPLE_PORT");
+//!     let config: AppConfig = state.to_type(-1).unwrap();
+//!     assert_eq!(config.port, 8080);
+//!
+//!     state.do_string("return {port = 1000}");
+//!     assert!(state.to_type::<AppConfig>(-1).is_none());
+//! }
+//! ```
+//!
+//! ## Raise errors of methods
+//!
+//! Macro `lua_try_method` generates a function with the name of a method which
+//! returns `Result`. `Ok` value is returned to script and `Err` raises error with
+//! its description, so scripts could catch it with `pcall`:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use std::io;
+//!
+//! #[derive(Clone)]
+//! struct File {
+//!     content: Option<String>,
+//! }
+//!
+//! impl File {
+//!     fn read(&self) -> Result<String, io::Error> {
+//!         self.content.clone().ok_or_else(|| io::Error::new(io::ErrorKind::Other, "disk on fire"))
+//!     }
+//! }
+//!
+//! lua_try_method!(File { fn read(&self) -> Result<String, io::Error> });
+//!
+//! lua_type!(File {
+//!     methods: { read => read },
+//!     meta: { },
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     File::attach(&mut state);
+//!
+//!     state.push(File { content: Some("hello".to_owned()) });
+//!     state.set_global("good");
+//!     state.push(File { content: None });
+//!     state.set_global("bad");
+//!
+//!     state.do_string("return good:read()");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "hello");
+//!
+//!     state.do_string("local ok, err = pcall(bad.read, bad) return ok, err");
+//!     assert_eq!(state.to_type::<bool>(-2), Some(false));
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "disk on fire");
+//! }
+//! ```
+//!
+//! ## Read arrays of flags to bitsets
+//!
+//! With `bitset` feature macro `lua_bitset_type` creates wrapper of `BitVec`
+//! which reads arrays of booleans or integers `0` and `1`:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! # #[cfg(feature = "bitset")]
+//! lua_bitset_type!(Flags);
+//!
+//! # #[cfg(feature = "bitset")]
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return {true, false, true}");
+//!     let Flags(bits) = state.to_type(-1).unwrap();
+//!     assert_eq!(bits.len(), 3);
+//!     assert!(bits[0] && !bits[1] && bits[2]);
+//!
+//!     state.push(Flags(bits));
+//!     let Flags(restored) = state.to_type(-1).unwrap();
+//!     assert_eq!(restored.iter().by_vals().collect::<Vec<_>>(), vec![true, false, true]);
+//!
+//!     state.do_string("return {1, 0, 2}");
+//!     assert!(state.to_type::<Flags>(-1).is_none());
+//! }
+//! # #[cfg(not(feature = "bitset"))]
+//! # fn main() { }
+//! ```
+//!
+//! ## Limit rate of calls
+//!
+//! Macro `lua_rate_limited_fn` wraps a function with `RateLimiter`, calls over
+//! the limit raise error:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! unsafe extern "C" fn fetch_impl(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     state.push_string("data");
+//!     1
+//! }
+//!
+//! lua_rate_limited_fn!(fetch, max_per_sec = 3, fetch_impl);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(fetch));
+//!     state.set_global("fetch");
+//!
+//!     for _ in 0..3 {
+//!         assert!(!state.do_string("return fetch()").is_err());
+//!     }
+//!     assert!(state.do_string("return fetch()").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("rate limit exceeded for `fetch`"));
+//! }
+//! ```
+//!
+//! ## Push arrays from iterators
+//!
+//! Macro `push_iter_array` pushes items of an iterator to a new array as they are
+//! produced:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_array_type!(Numbers<Integer>);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     push_iter_array!(state, (1..6).filter(|x: &Integer| x % 2 == 1).map(|x| x * 10));
+//!     let Numbers(numbers) = state.to_type(-1).unwrap();
+//!     assert_eq!(numbers, vec![10, 30, 50]);
+//! }
+//! ```
+//!
+//! ## Declare functions with fixed arity
+//!
+//! Macro `lua_fn_arity` declares a function which checks count of arguments
+//! before the body runs. The arity is the count of listed types, so it can't differ
+//! from the converted arguments. The body takes `&mut State` with the arguments
+//! and returns count of results or position of a wrong argument:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_fn_arity!(add2, [Integer, Integer], |state, (a, b)| {
+//!     state.push(a + b);
+//!     Ok(1)
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(add2));
+//!     state.set_global("add2");
+//!
+//!     assert!(!state.do_string("return add2(1, 2)").is_err());
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(3));
+//!
+//!     assert!(state.do_string("return add2(1, 2, 3)").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("`add2` expects 2 arguments, got 3"));
+//!
+//!     assert!(state.do_string("return add2(1)").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("`add2` expects 2 arguments, got 1"));
+//!
+//!     assert!(state.do_string("return add2(1, 'x')").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #2"));
+//! }
+//! ```
+//!
+//! ## Convert userdata to trait objects
+//!
+//! `TypeRegistry` keeps handlers for names of metatables and macro
+//! `to_trait_object` uses it to convert values of any registered type:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Number};
+//! # use lua_macros::TypeRegistry;
+//!
+//! trait Shape {
+//!     fn area(&self) -> Number;
+//! }
+//!
+//! #[derive(Clone)]
+//! struct Square(Number);
+//!
+//! #[derive(Clone)]
+//! struct Rect(Number, Number);
+//!
+//! impl Shape for Square {
+//!     fn area(&self) -> Number { self.0 * self.0 }
+//! }
+//!
+//! impl Shape for Rect {
+//!     fn area(&self) -> Number { self.0 * self.1 }
+//! }
+//!
+//! lua_userdata!(Square);
+//! lua_userdata!(Rect);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     Square::attach(&mut state);
+//!     Rect::attach(&mut state);
+//!
+//!     let mut registry: TypeRegistry<dyn Shape> = TypeRegistry::new();
+//!     registry.register(Square::meta_name(), |state, idx| {
+//!         state.to_type::<Square>(idx).map(|s| Box::new(s) as Box<dyn Shape>)
+//!     });
+//!     registry.register(Rect::meta_name(), |state, idx| {
+//!         state.to_type::<Rect>(idx).map(|r| Box::new(r) as Box<dyn Shape>)
+//!     });
+//!
+//!     state.push(Square(3.0));
+//!     state.push(Rect(2.0, 5.0));
+//!     state.push(1.0);
+//!
+//!     assert_eq!(to_trait_object!(state, -3, registry, dyn Shape).unwrap().area(), 9.0);
+//!     assert_eq!(to_trait_object!(state, -2, registry, dyn Shape).unwrap().area(), 10.0);
+//!     assert!(to_trait_object!(state, -1, registry, dyn Shape).is_none());
+//! }
+//! ```
+//!
+//! ## Yield from Rust functions
+//!
+//! Macro `lua_yielding_fn` declares a function which yields values pushed by the
+//! first block. When the coroutine is resumed the second block runs as a
+//! continuation: values passed to `coroutine.resume` are on top of the stack and
+//! it returns count of results:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Number};
+//!
+//! lua_yielding_fn!(fn sleep(state) {
+//!     let (dt,) = convert_arguments!(state, Number).unwrap();
+//!     state.push(dt * 2.0);
+//!     1
+//! } resume(state) {
+//!     let (reason,) = convert_arguments!(@strict false, state, String).unwrap();
+//!     state.push_string(&format!("woken by {}", reason));
+//!     1
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     state.push_fn(Some(sleep));
+//!     state.set_global("sleep");
+//!
+//!     state.do_string(r#"
+//!         local co = coroutine.create(function()
+//!             local result = sleep(1.5)
+//!             return result
+//!         end)
+//!         local _, yielded = coroutine.resume(co)
+//!         local _, result = coroutine.resume(co, "timer")
+//!         return yielded, result
+//!     "#);
+//!     assert_eq!(state.to_type::<Number>(-2), Some(3.0));
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "woken by timer");
+//! }
+//! ```
+//!
+//! ## Read tagged tables to enums with data
+//!
+//! Macro `lua_variant_enum` declares enum which reads a table by its tag field.
+//! The tag is name of variant in lower case, then fields of the variant are read:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_variant_enum!(#[derive(Debug, PartialEq)] Command, tag = "kind" {
+//!     Move { dx: Integer, dy: Integer },
+//!     Say { text: String },
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return {kind = 'move', dx = 1, dy = 0}");
+//!     assert_eq!(state.to_type::<Command>(-1), Some(Command::Move { dx: 1, dy: 0 }));
+//!
+//!     state.do_string("return {kind = 'say', text = 'hi'}");
+//!     assert_eq!(state.to_type::<Command>(-1), Some(Command::Say { text: "hi".to_owned() }));
+//!
+//!     state.do_string("return {kind = 'jump'}");
+//!     assert_eq!(state.to_type::<Command>(-1), None);
+//!
+//!     state.do_string("return {kind = 'move', dx = 1}");
+//!     assert_eq!(state.to_type::<Command>(-1), None);
+//! }
+//! ```
+//!
+//! ## Construct enums with data in scripts
+//!
+//! Flag `constructors` of `lua_variant_enum` adds `attach` which registers a
+//! global table with constructors of variants:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_variant_enum!(#[derive(Debug, PartialEq)] Command, tag = "kind" {
+//!     Move { dx: Integer, dy: Integer },
+//!     Stop { },
+//! }, constructors);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     Command::attach(&mut state);
+//!
+//!     state.do_string("return Command.Move(1, 0)");
+//!     assert_eq!(state.to_type::<Command>(-1), Some(Command::Move { dx: 1, dy: 0 }));
+//!
+//!     state.do_string("local cmd = Command.Move(2, 3) return cmd.kind, cmd.dy");
+//!     assert_eq!(state.to_type::<String>(-2).unwrap(), "move");
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(3));
+//!
+//!     state.do_string("return Command.Stop()");
+//!     assert_eq!(state.to_type::<Command>(-1), Some(Command::Stop { }));
+//!
+//!     assert!(state.do_string("return Command.Move('left')").is_err());
+//! }
+//! ```
+//!
+//! ## Detect leaks of Lua's memory
+//!
+//! Macro `with_tracking_allocator` runs a block with a new state which allocates
+//! memory through `AllocTracker`. It returns net bytes left allocated after full
+//! garbage collection and panics if they exceed the threshold:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//!
+//! fn main() {
+//!     let net = with_tracking_allocator!(state, threshold = 4096, {
+//!         state.do_string("local t = {} for i = 1, 1000 do t[i] = {i} end");
+//!     });
+//!     assert!(net.abs() < 4096);
+//! }
+//! ```
+//!
+//! ## Poll coroutines as futures
+//!
+//! Macro `lua_future` wraps a coroutine to `LuaFuture`. Every poll resumes it,
+//! yield makes the future pending and return makes it ready:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//! # use lua_macros::LuaFuture;
+//! # use std::future::Future;
+//! # use std::pin::Pin;
+//! # use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+//!
+//! fn noop_waker() -> Waker {
+//!     fn clone(_: *const ()) -> RawWaker { RawWaker::new(::std::ptr::null(), &VTABLE) }
+//!     fn noop(_: *const ()) { }
+//!     static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+//!     unsafe { Waker::from_raw(RawWaker::new(::std::ptr::null(), &VTABLE)) }
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     state.do_string("function task(n) coroutine.yield() coroutine.yield() return n * 2 end");
+//!
+//!     let mut thread = state.new_thread();
+//!     thread.get_global("task");
+//!     thread.push(21);
+//!     let mut future: LuaFuture<Integer> = lua_future!(state, thread);
+//!
+//!     let waker = noop_waker();
+//!     let mut cx = Context::from_waker(&waker);
+//!     assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Pending);
+//!     assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Pending);
+//!     assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Ready(Ok(42)));
+//! }
+//! ```
+//!
+//! ## Collect metrics of scripts
+//!
+//! Macro `lua_metrics` sets global `metrics` table with `counter(name, n)` (`n` is 1
+//! by default) and `gauge(name, v)` functions which forward to `MetricsSink`:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Number};
+//! # use lua_macros::MetricsSink;
+//! # use std::cell::RefCell;
+//! # use std::collections::HashMap;
+//! # use std::rc::Rc;
+//!
+//! #[derive(Default)]
+//! struct Telemetry {
+//!     counters: HashMap<String, Number>,
+//! }
+//!
+//! impl MetricsSink for Telemetry {
+//!     fn counter(&mut self, name: &str, n: Number) {
+//!         *self.counters.entry(name.to_owned()).or_insert(0.0) += n;
+//!     }
+//!
+//!     fn gauge(&mut self, _name: &str, _value: Number) {
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     let telemetry = Rc::new(RefCell::new(Telemetry::default()));
+//!     lua_metrics!(state, telemetry.clone());
+//!
+//!     state.do_string("metrics.counter('hits', 2) metrics.counter('hits') metrics.gauge('load', 0.5)");
+//!     assert_eq!(telemetry.borrow().counters["hits"], 3.0);
+//! }
+//! ```
+//!
+//! ## Read values with serde
+//!
+//! With `serde` feature macro `from_lua_serde` reads a value to any type which
+//! implements `Deserialize`. Non-empty sequences are read as sequences and other
+//! tables as maps, but fields which expect a sequence or a map read tables as expected.
+//! Missing fields follow serde rules, so `Option` fields become `None`:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # #[cfg(feature = "serde")]
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # use lua_macros::lua::State;
+//!
+//! # #[cfg(feature = "serde")]
+//! #[derive(Deserialize, Debug, PartialEq)]
+//! struct Window {
+//!     width: u32,
+//!     height: u32,
+//! }
+//!
+//! # #[cfg(feature = "serde")]
+//! #[derive(Deserialize, Debug, PartialEq)]
+//! struct Config {
+//!     name: String,
+//!     title: Option<String>,
+//!     window: Window,
+//!     tags: Vec<String>,
+//! }
+//!
+//! # #[cfg(feature = "serde")]
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return {name = 'app', window = {width = 800, height = 600}, tags = {'a', 'b'}}");
+//!     let config = from_lua_serde!(state, -1, Config).unwrap();
+//!     assert_eq!(config, Config {
+//!         name: "app".to_owned(),
+//!         title: None,
+//!         window: Window { width: 800, height: 600 },
+//!         tags: vec!["a".to_owned(), "b".to_owned()],
+//!     });
+//!
+//!     state.do_string("return {name = 'app'}");
+//!     assert!(from_lua_serde!(state, -1, Config).is_err());
+//! }
+//! # #[cfg(not(feature = "serde"))]
+//! # fn main() { }
+//! ```
+//!
+//! ## Push values with serde
+//!
+//! With `serde` feature macro `to_lua_serde` pushes any value which implements
+//! `Serialize`. Structs and maps become tables, sequences become arrays and `None`
+//! becomes `nil`. Unit variants of enums become strings and other variants become
+//! tables with a single field `variant = value` (`from_lua_serde` reads them back):
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # #[cfg(feature = "serde")]
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # use lua_macros::lua::State;
+//!
+//! # #[cfg(feature = "serde")]
+//! #[derive(Serialize)]
+//! enum Shape {
+//!     Empty,
+//!     Circle(f64),
+//!     Rect { w: f64, h: f64 },
+//! }
+//!
+//! # #[cfg(feature = "serde")]
+//! #[derive(Serialize)]
+//! struct Scene {
+//!     name: String,
+//!     owner: Option<String>,
+//!     shapes: Vec<Shape>,
+//! }
+//!
+//! # #[cfg(feature = "serde")]
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     let scene = Scene {
+//!         name: "demo".to_owned(),
+//!         owner: None,
+//!         shapes: vec![Shape::Empty, Shape::Circle(2.0), Shape::Rect { w: 3.0, h: 4.0 }],
+//!     };
+//!     to_lua_serde!(state, &scene).unwrap();
+//!     state.set_global("scene");
+//!     state.do_string("return scene.name, scene.owner, #scene.shapes, scene.shapes[1], \
+//!                      scene.shapes[2].Circle, scene.shapes[3].Rect.h");
+//!     assert_eq!(state.to_type::<String>(-6).unwrap(), "demo");
+//!     assert!(state.is_nil(-5));
+//!     assert_eq!(state.to_type::<i64>(-4), Some(3));
+//!     assert_eq!(state.to_type::<String>(-3).unwrap(), "Empty");
+//!     assert_eq!(state.to_type::<f64>(-2), Some(2.0));
+//!     assert_eq!(state.to_type::<f64>(-1), Some(4.0));
+//! }
+//! # #[cfg(not(feature = "serde"))]
+//! # fn main() { }
+//! ```
+//!
+//! ## Pass context to functions
+//!
+//! Macro `lua_ctx_fn` declares a function which takes a context kept by `set_context`
+//! in the registry as the first parameter. Other parameters are converted from arguments:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use lua_macros::{set_context, with_context};
+//!
+//! struct AppContext {
+//!     commands: Vec<String>,
+//! }
+//!
+//! lua_ctx_fn!("app", fn handle(ctx: &mut AppContext, cmd: String) -> i64 {
+//!     ctx.commands.push(cmd);
+//!     ctx.commands.len() as i64
+//! });
+//!
+//! lua_ctx_fn!("app", fn clear(ctx: &mut AppContext) {
+//!     ctx.commands.clear();
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     set_context(&mut state, "app", AppContext { commands: Vec::new() });
+//!     state.push_fn(Some(handle));
+//!     state.set_global("handle");
+//!     state.push_fn(Some(clear));
+//!     state.set_global("clear");
+//!
+//!     assert!(!state.do_string("handle('start') return handle('stop')").is_err());
+//!     assert_eq!(state.to_type::<i64>(-1), Some(2));
+//!     let commands = with_context(&mut state, "app", |ctx: &mut AppContext| ctx.commands.clone());
+//!     assert_eq!(commands.unwrap(), vec!["start", "stop"]);
+//!
+//!     assert!(!state.do_string("clear() return handle('again')").is_err());
+//!     assert_eq!(state.to_type::<i64>(-1), Some(1));
+//! }
+//! ```
+//!
+//! ## Read arrays of tables by columns
+//!
+//! Macro `lua_columnar_type` declares a struct of arrays. It reads an array of tables
+//! and puts every field to its own vector:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_columnar_type!(Points { x: Integer, y: Integer });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return {{x = 1, y = 2}, {x = 3, y = 4}}");
+//!     let points = state.to_type::<Points>(-1).unwrap();
+//!     assert_eq!(points.x, vec![1, 3]);
+//!     assert_eq!(points.y, vec![2, 4]);
+//!
+//!     state.do_string("return {{x = 1, y = 2}, {x = 3}}");
+//!     assert!(state.to_type::<Points>(-1).is_none());
+//! }
+//! ```
+//!
+//! ## Take any arguments as JSON
+//!
+//! With `json` feature macro `lua_json_args_fn` declares a function which takes any
+//! count of arguments as a vector of JSON values:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use std::cell::RefCell;
+//!
+//! thread_local! {
+//!     static RECORDS: RefCell<Vec<String>> = RefCell::new(Vec::new());
+//! }
+//!
+//! # #[cfg(feature = "json")]
+//! lua_json_args_fn!(fn log(args) {
+//!     assert_eq!(args[0], 1);
+//!     assert_eq!(args[1], "two");
+//!     assert_eq!(args[2]["a"], 3);
+//!     RECORDS.with(|records| {
+//!         records.borrow_mut().extend(args.iter().map(|value| value.to_string()));
+//!     });
+//! });
+//!
+//! # #[cfg(feature = "json")]
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(log));
+//!     state.set_global("log");
+//!
+//!     state.do_string("log(1, 'two', {a = 3})");
+//!     RECORDS.with(|records| {
+//!         assert_eq!(*records.borrow(), vec!["1", "\"two\"", "{\"a\":3}"]);
+//!     });
+//! }
+//! # #[cfg(not(feature = "json"))]
+//! # fn main() { }
+//! ```
+//!
+//! ## Bind properties to fields
+//!
+//! Macro `lua_field` adds a property bound to a field of userdata. It wraps the
+//! handlers of the attached metatable, so every call adds one more property:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! #[derive(Clone)]
+//! struct Player {
+//!     hp: Integer,
+//!     name: String,
+//! }
+//!
+//! lua_userdata!(Player);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     Player::attach(&mut state);
+//!     lua_field!(state, Player, "hp", hp: Integer);
+//!     lua_field!(state, Player, "name", name: String);
+//!
+//!     state.push(Player { hp: 100, name: "hero".to_owned() });
+//!     state.set_global("player");
+//!     state.do_string("player.hp = player.hp - 30 player.name = player.name .. '!'");
+//!
+//!     state.get_global("player");
+//!     let player = state.to_type::<Player>(-1).unwrap();
+//!     assert_eq!(player.hp, 70);
+//!     assert_eq!(player.name, "hero!");
+//! }
+//! ```
+//!
+//! ## Compile chunks once
+//!
+//! Macro `compile_chunk` loads a chunk without running it and keeps its function in
+//! the registry. Method `run` calls it with arguments and pushes results:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     let chunk = compile_chunk!(state, "local a, b = ... return a + b, a * b").unwrap();
+//!     assert_eq!(chunk.run(&mut state, &[&1i64, &2i64]), Ok(2));
+//!     assert_eq!(state.to_type::<Integer>(-2), Some(3));
+//!     state.pop(2);
+//!     assert_eq!(chunk.run(&mut state, &[&10i64, &20i64]), Ok(2));
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(200));
+//!     state.pop(2);
+//!
+//!     assert!(chunk.run(&mut state, &[]).is_err());
+//!     chunk.release(&mut state);
+//!     assert!(compile_chunk!(state, "return +").is_err());
+//! }
+//! ```
+//!
+//! ## Read positional tables
+//!
+//! Macro `lua_positional` reads items of an array to a tuple. Missing items take
+//! defaults, and `Err` contains the position of a wrong item:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer, Number};
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return {5, 2.5, 'y'}");
+//!     let full = lua_positional!(state, -1, [Integer, Number = 1.0, String = "x"]);
+//!     assert_eq!(full, Ok((5, 2.5, "y".to_owned())));
+//!
+//!     state.do_string("return {5}");
+//!     let short = lua_positional!(state, -1, [Integer, Number = 1.0, String = "x"]);
+//!     assert_eq!(short, Ok((5, 1.0, "x".to_owned())));
+//!
+//!     state.do_string("return {nil, 2.5}");
+//!     assert_eq!(lua_positional!(state, -1, [Integer, Number = 1.0]), Err(1));
+//! }
+//! ```
+//!
+//! ## Check interfaces of objects
+//!
+//! Macro `require_interface` checks that an object has functions in all the listed
+//! fields and returns `MissingMethod` with the first missing one. Fields are read in
+//! protected mode, so an object which can't be indexed lacks all methods:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use lua_macros::MissingMethod;
+//!
+//! #[derive(Clone)]
+//! struct Plain;
+//!
+//! lua_userdata!(Plain);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!
+//!     state.do_string("return {update = function() end, render = function() end}");
+//!     assert_eq!(require_interface!(state, -1, ["update", "render"]), Ok(()));
+//!
+//!     state.do_string("return {update = function() end, render = 'no'}");
+//!     let missing = require_interface!(state, -1, ["update", "render"]);
+//!     assert_eq!(missing, Err(MissingMethod("render".to_owned())));
+//!
+//!     Plain::attach(&mut state);
+//!     state.push(Plain);
+//!     let missing = require_interface!(state, -1, ["update"]);
+//!     assert_eq!(missing, Err(MissingMethod("update".to_owned())));
+//!
+//!     state.do_string("return setmetatable({}, {__index = function() error('locked') end})");
+//!     let missing = require_interface!(state, -1, ["update"]);
+//!     assert_eq!(missing, Err(MissingMethod("update".to_owned())));
+//! }
+//! ```
+//!
+//! ## Optional arguments
+//!
+//! Slot `?Type` of `convert_arguments` gives `Option<Type>`. It's `None` if the
+//! argument is omitted or `nil`, but a wrong value is still an error. Optional slots
+//! have to be the last ones (see `Option<Type>` for `nil` in the middle):
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer, Number};
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! unsafe extern "C" fn fun(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let (_int, _float, name) = convert_arguments!(state, Integer, Number, ?String)
+//!         .unwrap_or_else(|pos| {
+//!             state.arg_error(pos, "wrong argument");
+//!             unreachable!()
+//!         });
+//!     state.push(name.unwrap_or_else(|| "none".to_owned()));
+//!     1
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(fun));
+//!     state.set_global("fun");
+//!
+//!     state.do_string("return fun(1, 2.3)");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "none");
+//!
+//!     state.do_string("return fun(1, 2.3, nil)");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "none");
+//!
+//!     state.do_string("return fun(1, 2.3, 'name')");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "name");
+//!
+//!     assert!(state.do_string("return fun(1, 2.3, {})").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #3"));
+//!
+//!     assert!(state.do_string("return fun(1)").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #2"));
+//! }
+//! ```
+//!
+//! ## Read structs with extra keys
+//!
+//! Macro `lua_struct` declares a struct which is read from a table by names of its
+//! fields. Form `Name { fields } + extras: Map` collects other string keys to the
+//! map instead of ignoring them. `LuaValue` keeps any plain value of Lua:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//! # use lua_macros::LuaValue;
+//! # use std::collections::HashMap;
+//!
+//! lua_struct!(Config { retries: Integer } + extras: HashMap<String, LuaValue>);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return {retries = 3, mode = 'fast', level = 2}");
+//!     let config = state.to_type::<Config>(-1).unwrap();
+//!     assert_eq!(config.retries, 3);
+//!     assert_eq!(config.extras.len(), 2);
+//!     assert_eq!(config.extras["mode"], LuaValue::String("fast".to_owned()));
+//!     assert_eq!(config.extras["level"], LuaValue::Integer(2));
+//!
+//!     state.do_string("return {mode = 'fast'}");
+//!     assert!(state.to_type::<Config>(-1).is_none());
+//! }
+//! ```
+//!
+//! ## Variadic arguments
+//!
+//! Slot `..Type` of `convert_arguments` takes all remaining arguments to a vector.
+//! It has to be the last slot, and the position of a wrong argument is returned as usual:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! unsafe extern "C" fn sum(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let (label, values) = convert_arguments!(state, String, ..Integer)
+//!         .unwrap_or_else(|pos| {
+//!             state.arg_error(pos, "wrong argument");
+//!             unreachable!()
+//!         });
+//!     let total: Integer = values.iter().sum();
+//!     state.push(format!("{}: {}", label, total));
+//!     1
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(sum));
+//!     state.set_global("sum");
+//!
+//!     state.do_string("return sum('total', 1, 2, 3)");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "total: 6");
+//!
+//!     state.do_string("return sum('empty')");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "empty: 0");
+//!
+//!     assert!(state.do_string("return sum('bad', 1, {}, 3)").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #3"));
+//! }
+//! ```
+//!
+//! ## Redirect output to writers
+//!
+//! Macro `lua_writer_fn` sets a global function which converts its arguments like
+//! `tostring` does and writes them to a writer kept in the registry:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use std::cell::RefCell;
+//! # use std::io::{self, Write};
+//! # use std::rc::Rc;
+//!
+//! #[derive(Clone, Default)]
+//! struct Buffer(Rc<RefCell<Vec<u8>>>);
+//!
+//! impl Write for Buffer {
+//!     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+//!         self.0.borrow_mut().write(buf)
+//!     }
+//!
+//!     fn flush(&mut self) -> io::Result<()> {
+//!         Ok(())
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     let buffer = Buffer::default();
+//!     lua_writer_fn!(state, "write", buffer.clone());
+//!
+//!     state.do_string("write('hello', ' ', 42) write(true)");
+//!     assert_eq!(&*buffer.0.borrow(), b"hello 42true");
+//! }
+//! ```
+//!
+//! ## Typed errors of arguments
+//!
+//! Form `convert_arguments!(@typed state, ...)` returns `ArgError` with position,
+//! expected type and the type which was got instead of a bare position:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer, Number};
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! unsafe extern "C" fn fun(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     match convert_arguments!(@typed state, Integer, Number) {
+//!         Ok(_) => state.push_string("OK"),
+//!         Err(err) => state.push_string(&err.to_string()),
+//!     };
+//!     1
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(fun));
+//!     state.set_global("fun");
+//!
+//!     state.do_string("return fun(1, 'x')");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "bad argument #2 (number expected, got string)");
+//!
+//!     state.do_string("return fun(1)");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "bad argument #2 (number expected, got no value)");
+//!
+//!     state.do_string("return fun(1, 2.5)");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "OK");
+//!
+//!     state.set_top(0);
+//!     state.do_string("return 1, 'x', 3");
+//!     let err = convert_arguments!(@typed state, Integer, ..Integer).unwrap_err();
+//!     assert_eq!(err.to_string(), "bad argument #2 (integer expected, got string)");
+//!
+//!     state.set_top(0);
+//!     state.do_string("return 1, 2, 'x'");
+//!     let err = convert_arguments!(@typed state, Integer, ..Integer).unwrap_err();
+//!     assert_eq!(err.to_string(), "bad argument #3 (integer expected, got string)");
+//! }
+//! ```
+//!
+//! ## Freeze userdata
+//!
+//! Flag `freeze` of `lua_userdata` adds method `freeze` which makes the instance
+//! read-only. Put it after flags which set `__newindex` (like `uservalue`),
+//! because it checks the instance before them:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! #[derive(Clone)]
+//! struct Builder;
+//!
+//! lua_userdata!(Builder; uservalue, freeze);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     Builder::attach(&mut state);
+//!     state.push(Builder);
+//!     state.set_global("builder");
+//!
+//!     assert!(!state.do_string("builder.name = 'first' builder:freeze()").is_err());
+//!     assert!(state.do_string("builder.name = 'second'").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("frozen"));
+//!
+//!     state.do_string("return builder.name");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "first");
+//!
+//!     state.get_global("builder");
+//!     assert!(Builder::is_frozen(&mut state, -1));
+//! }
+//! ```
+//!
+//! ## Describe modules
+//!
+//! Macro `lua_module_info` sets `_VERSION` and `_CAPABILITIES` to a module table,
+//! so scripts can check features before use:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.do_string("mymod = {ping = function() return 'pong' end}");
+//!
+//!     lua_module_info!(state, "mymod", version = "1.2.0", capabilities = ["async", "net"]);
+//!
+//!     state.do_string("return mymod._VERSION, mymod._CAPABILITIES.net == true, mymod._CAPABILITIES.fs == nil");
+//!     assert_eq!(state.to_type::<String>(-3).unwrap(), "1.2.0");
+//!     assert_eq!(state.to_type::<bool>(-2), Some(true));
+//!     assert_eq!(state.to_type::<bool>(-1), Some(true));
+//! }
+//! ```
+//!
+//! ## Read tables with sorted keys
+//!
+//! Macro `lua_ordered_table_type` works like `lua_table_type`, but keeps the pairs
+//! in `BTreeMap`, so they are iterated in order of keys:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_ordered_table_type!(Settings<String, Integer>);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.do_string("return {zeta = 3, alpha = 1, mid = 2}");
+//!     let Settings(settings) = state.to_type(-1).unwrap();
+//!     let keys: Vec<&str> = settings.keys().map(String::as_str).collect();
+//!     assert_eq!(keys, vec!["alpha", "mid", "zeta"]);
+//!
+//!     state.push(Settings(settings.clone()));
+//!     let Settings(copy) = state.to_type(-1).unwrap();
+//!     assert_eq!(copy, settings);
+//! }
+//! ```
+//!
+//! ## Own conversions
+//!
+//! Trait `Codec` sets own representation of a type, macros `push_codec` and
+//! `read_codec` use it:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Index};
+//! # use lua_macros::Codec;
+//!
+//! #[derive(Debug, PartialEq)]
+//! struct Rgb(u8, u8, u8);
+//!
+//! impl Codec for Rgb {
+//!     fn encode(&self, state: &mut State) {
+//!         state.push_string(&format!("#{:02x}{:02x}{:02x}", self.0, self.1, self.2));
+//!     }
+//!
+//!     fn decode(state: &mut State, index: Index) -> Option<Self> {
+//!         let text = state.to_type::<String>(index)?;
+//!         if text.len() != 7 || !text.starts_with('#') {
+//!             return None;
+//!         }
+//!         let part = |n: usize| u8::from_str_radix(&text[n..n + 2], 16).ok();
+//!         Some(Rgb(part(1)?, part(3)?, part(5)?))
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     push_codec!(state, &Rgb(255, 128, 0));
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "#ff8000");
+//!     assert_eq!(read_codec!(state, -1, Rgb), Some(Rgb(255, 128, 0)));
+//!
+//!     state.push_string("red");
+//!     assert_eq!(read_codec!(state, -1, Rgb), None);
+//! }
+//! ```
+//!
+//! ## Clean up the stack on panic
+//!
+//! `auto_cleanup` restores the stack with a guard, so it's restored even if the
+//! block panics:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use std::panic::{self, AssertUnwindSafe};
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push(1);
+//!
+//!     let result = panic::catch_unwind(AssertUnwindSafe(|| {
+//!         auto_cleanup!(state, {
+//!             state.push(2);
+//!             state.push(3);
+//!             panic!("interrupted");
+//!         })
+//!     }));
+//!     assert!(result.is_err());
+//!     assert_eq!(state.get_top(), 1);
+//!
+//!     let value = auto_cleanup!(state, {
+//!         state.push(4);
+//!         state.to_type::<i64>(-1)
+//!     });
+//!     assert_eq!(value, Some(4));
+//!     assert_eq!(state.get_top(), 1);
+//! }
+//! ```
+//!
+//! ## Guard the stack for scope
+//!
+//! Macro `stack_guard` makes a guard which restores the stack when it's dropped.
+//! It protects any scope, and `release` disarms it. The guard borrows the state,
+//! so the state is used through the guard:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! fn push_values(state: &mut State, count: i64, keep: bool) {
+//!     let mut guard = stack_guard!(state);
+//!     for value in 0..count {
+//!         guard.push(value);
+//!     }
+//!     if keep {
+//!         guard.release();
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     push_values(&mut state, 3, false);
+//!     assert_eq!(state.get_top(), 0);
+//!
+//!     push_values(&mut state, 3, true);
+//!     assert_eq!(state.get_top(), 3);
+//! }
+//! ```
+//!
+//! ## Guard functions by permissions
+//!
+//! Macro `lua_guarded_fn` declares a function which checks a permission granted
+//! by `grant_permission` before its body:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use lua_macros::{grant_permission, revoke_permission};
+//!
+//! lua_guarded_fn!(perm = "fs.write", fn write_file(path: String, text: String) -> String {
+//!     format!("{} bytes to {}", text.len(), path)
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(write_file));
+//!     state.set_global("write_file");
+//!
+//!     assert!(state.do_string("return write_file('a.txt', 'hello')").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("permission denied: fs.write"));
+//!
+//!     grant_permission(&mut state, "fs.write");
+//!     state.do_string("return write_file('a.txt', 'hello')");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "5 bytes to a.txt");
+//!
+//!     revoke_permission(&mut state, "fs.write");
+//!     assert!(state.do_string("return write_file('a.txt', 'hello')").is_err());
+//! }
+//! ```
+//!
+//! ## Convert objects once
+//!
+//! Macro `memo_convert` keeps conversions of tables and userdata in `ConvertCache`
+//! by identity of objects, so the same object is converted once:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Index, Integer, FromLua};
+//! # use lua_macros::ConvertCache;
+//! # use std::cell::Cell;
+//!
+//! thread_local! {
+//!     static CONVERSIONS: Cell<usize> = Cell::new(0);
+//!     static ALIVE: Cell<isize> = Cell::new(0);
+//! }
+//!
+//! #[derive(Debug, PartialEq)]
+//! struct Sum(Integer);
+//!
+//! impl Clone for Sum {
+//!     fn clone(&self) -> Self {
+//!         ALIVE.with(|alive| alive.set(alive.get() + 1));
+//!         Sum(self.0)
+//!     }
+//! }
+//!
+//! impl Drop for Sum {
+//!     fn drop(&mut self) {
+//!         ALIVE.with(|alive| alive.set(alive.get() - 1));
+//!     }
+//! }
+//!
+//! impl FromLua for Sum {
+//!     fn from_lua(state: &mut State, index: Index) -> Option<Self> {
+//!         CONVERSIONS.with(|count| count.set(count.get() + 1));
+//!         let index = state.abs_index(index);
+//!         let mut sum = 0;
+//!         for idx in 1..state.raw_len(index) as Integer + 1 {
+//!             state.geti(index, idx);
+//!             sum += state.to_type::<Integer>(-1)?;
+//!             state.pop(1);
+//!         }
+//!         ALIVE.with(|alive| alive.set(alive.get() + 1));
+//!         Some(Sum(sum))
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     let mut cache = ConvertCache::new();
+//!
+//!     state.do_string("data = {1, 2, 3}");
+//!     for _ in 0..2 {
+//!         state.get_global("data");
+//!         assert_eq!(memo_convert!(state, -1, Sum, cache).map(|sum| sum.0), Some(6));
+//!         state.pop(1);
+//!     }
+//!     assert_eq!(CONVERSIONS.with(Cell::get), 1);
+//!
+//!     state.do_string("return {1, 2, 3}");
+//!     assert_eq!(memo_convert!(state, -1, Sum, cache).map(|sum| sum.0), Some(6));
+//!     assert_eq!(CONVERSIONS.with(Cell::get), 2);
+//!     state.pop(1);
+//!
+//!     // Conversions are dropped with objects
+//!     state.do_string("data = nil collectgarbage()");
+//!     assert_eq!(ALIVE.with(Cell::get), 0);
+//!     cache.release(&mut state);
+//! }
+//! ```
+//!
+//! ## Drop userdata
+//!
+//! Metatable of `lua_userdata` has `__gc` which drops the value when Lua collects it.
+//! The value is dropped once even if the finalizer is called again:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use std::cell::Cell;
+//! # use std::rc::Rc;
+//!
+//! #[derive(Clone)]
+//! struct Resource {
+//!     drops: Rc<Cell<usize>>,
+//! }
+//!
+//! impl Drop for Resource {
+//!     fn drop(&mut self) {
+//!         self.drops.set(self.drops.get() + 1);
+//!     }
+//! }
+//!
+//! lua_userdata!(Resource);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     Resource::attach(&mut state);
+//!
+//!     let drops = Rc::new(Cell::new(0));
+//!     let resource = Resource { drops: drops.clone() };
+//!     state.push(resource.clone()); // Pushing clones the value again, the temporary is dropped
+//!     state.set_global("resource");
+//!     assert_eq!(drops.get(), 1);
+//!
+//!     state.do_string("resource = nil collectgarbage() collectgarbage()");
+//!     assert_eq!(drops.get(), 2);
+//!     drop(resource);
+//!     assert_eq!(drops.get(), 3);
+//! }
+//! ```
+//!
+//! ## Generators
+//!
+//! Macro `lua_generator_fn` declares a function which yields items of the iterator
+//! returned by its body. Items are taken lazily, so the iterator could be infinite:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_generator_fn!(fn fib() -> Integer {
+//!     ::std::iter::successors(Some((0, 1)), |&(a, b)| Some((b, a + b))).map(|(a, _)| a)
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     state.push_fn(Some(fib));
+//!     state.set_global("fib");
+//!
+//!     state.do_string("local next = coroutine.wrap(fib) \
+//!                      local values = {} \
+//!                      for i = 1, 5 do values[i] = next() end \
+//!                      return table.concat(values, ',')");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "0,1,1,2,3");
+//! }
+//! ```
+//!
+//! ## Call methods of userdata
+//!
+//! Functions passed to `lua_userdata` with names without `__` prefix become methods.
+//! They are kept in `__index` table, while metamethods stay in metatable:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! #[derive(Clone, Debug, PartialEq)]
+//! enum UserEnum {
+//!     One,
+//!     Two,
+//! }
+//!
+//! unsafe extern "C" fn describe(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let (value,) = convert_arguments!(state, UserEnum).unwrap();
+//!     state.push_string(&format!("value {:?}", value));
+//!     1
+//! }
+//!
+//! unsafe extern "C" fn to_string(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let (value,) = convert_arguments!(state, UserEnum).unwrap();
+//!     state.push_string(&format!("{:?}", value));
+//!     1
+//! }
+//!
+//! lua_userdata!(UserEnum, "describe" => describe, "__tostring" => to_string);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     UserEnum::attach(&mut state);
+//!
+//!     state.push(UserEnum::Two);
+//!     state.set_global("ud");
+//!
+//!     state.do_string("return ud:describe()");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "value Two");
+//!
+//!     state.do_string("return tostring(ud)");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "Two");
+//! }
+//! ```
+//!
+//! ## Read records
+//!
+//! Macro `lua_records_type` reads an array of tables to a vector of hash maps. It suits
+//! records with different sets of fields:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use lua_macros::LuaValue;
+//!
+//! lua_records_type!(Rows<String, LuaValue>);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return {{name = 'a', age = 1}, {name = 'b', admin = true}}");
+//!     let Rows(rows) = state.to_type::<Rows>(-1).unwrap();
+//!     assert_eq!(rows.len(), 2);
+//!     assert_eq!(rows[0]["name"], LuaValue::String("a".to_owned()));
+//!     assert_eq!(rows[0]["age"], LuaValue::Integer(1));
+//!     assert_eq!(rows[1]["name"], LuaValue::String("b".to_owned()));
+//!     assert_eq!(rows[1]["admin"], LuaValue::Boolean(true));
+//!
+//!     state.do_string("return {{name = 'a'}, 'b'}");
+//!     assert!(state.to_type::<Rows>(-1).is_none());
+//! }
+//! ```
+//!
+//! ## Print userdata
+//!
+//! Flag `tostring` of `lua_userdata` formats values with `Debug` for `tostring` and `print`:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! #[derive(Clone, Debug, PartialEq)]
+//! enum UserEnum {
+//!     One,
+//!     Two,
+//! }
+//!
+//! lua_userdata!(UserEnum; tostring);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     UserEnum::attach(&mut state);
+//!
+//!     state.push(UserEnum::Two);
+//!     state.set_global("ud");
+//!
+//!     state.do_string("return tostring(ud)");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "Two");
+//! }
+//! ```
+//!
+//! ## Log output of scripts
+//!
+//! With `log` feature macro `capture_print` replaces `print` with a function which
+//! sends records to the logger:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # #[cfg(feature = "log")]
+//! # use lua_macros::log::{self, Log, Metadata, Record, LevelFilter};
+//! # use std::sync::Mutex;
+//!
+//! static RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+//!
+//! struct Capture;
+//!
+//! # #[cfg(feature = "log")]
+//! impl Log for Capture {
+//!     fn enabled(&self, _: &Metadata) -> bool {
+//!         true
+//!     }
+//!
+//!     fn log(&self, record: &Record) {
+//!         let text = format!("{} {}: {}", record.level(), record.target(), record.args());
+//!         RECORDS.lock().unwrap().push(text);
+//!     }
+//!
+//!     fn flush(&self) {}
+//! }
+//!
+//! # #[cfg(feature = "log")]
+//! fn main() {
+//!     log::set_logger(&Capture).unwrap();
+//!     log::set_max_level(LevelFilter::Info);
+//!
+//!     let mut state = State::new();
+//!     capture_print!(state, level = Info);
+//!
+//!     state.do_string("print('hi') print(1, true)");
+//!     assert_eq!(*RECORDS.lock().unwrap(), vec!["INFO lua: hi", "INFO lua: 1\ttrue"]);
+//! }
+//! # #[cfg(not(feature = "log"))]
+//! # fn main() { }
+//! ```
+//!
+//! ## Name metatables of userdata
+//!
+//! Types with the same name from different modules need distinct metatables. Set the name
+//! explicitly with `name = "..."`:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! mod first {
+//!     #[derive(Clone, Debug, PartialEq)]
+//!     pub struct Handle(pub u8);
+//!
+//!     lua_userdata!(Handle, name = "first.Handle");
+//! }
+//!
+//! mod second {
+//!     #[derive(Clone, Debug, PartialEq)]
+//!     pub struct Handle(pub u8);
+//!
+//!     lua_userdata!(Handle, name = "second.Handle");
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     first::Handle::attach(&mut state);
+//!     second::Handle::attach(&mut state);
+//!     assert_eq!(first::Handle::meta_name(), "first.Handle");
+//!
+//!     state.push(first::Handle(1));
+//!     assert_eq!(state.to_type::<first::Handle>(-1), Some(first::Handle(1)));
+//!     assert!(state.to_type::<second::Handle>(-1).is_none());
+//! }
+//! ```
+//!
+//! ## Overload functions by arity
+//!
+//! Macro `lua_overload_fn` declares a function which chooses a variant by count of
+//! arguments:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_overload_fn!(fn sub {
+//!     (s: String, i: Integer) => s[i as usize - 1..].to_owned(),
+//!     (s: String, i: Integer, j: Integer) => s[i as usize - 1..j as usize].to_owned(),
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(sub));
+//!     state.set_global("sub");
+//!
+//!     state.do_string("return sub('hello', 2)");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "ello");
+//!
+//!     state.do_string("return sub('hello', 2, 3)");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "el");
+//!
+//!     assert!(state.do_string("return sub('hello')").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("`sub` expects 2 or 3 arguments, got 1"));
+//!
+//!     assert!(state.do_string("return sub('hello', 'x')").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #2"));
+//! }
+//! ```
+//!
+//! ## Compare userdata by value
+//!
+//! Flag `eq` of `lua_userdata` makes `==` of scripts compare values with `PartialEq`
+//! instead of identity:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! #[derive(Clone, Debug, PartialEq)]
+//! enum UserEnum {
+//!     One,
+//!     Two,
+//! }
+//!
+//! lua_userdata!(UserEnum; eq);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     UserEnum::attach(&mut state);
+//!
+//!     state.push(UserEnum::One);
+//!     state.set_global("a");
+//!     state.push(UserEnum::One);
+//!     state.set_global("b");
+//!     state.push(UserEnum::Two);
+//!     state.set_global("c");
+//!
+//!     state.do_string("return a == b, a == c, a == {}");
+//!     assert_eq!(state.to_bool(-3), true);
+//!     assert_eq!(state.to_bool(-2), false);
+//!     assert_eq!(state.to_bool(-1), false);
+//! }
+//! ```
+//!
+//! ## Undo changes of userdata
+//!
+//! Macro `lua_snapshottable` adds `snapshot` and `restore` methods to cloneable userdata:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! #[derive(Clone, Debug, PartialEq)]
+//! struct Document {
+//!     text: String,
+//! }
+//!
+//! lua_userdata!(Document);
+//! lua_snapshottable!(Document);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     Document::attach(&mut state);
+//!     Document::attach_snapshots(&mut state);
+//!     lua_field!(state, Document, "text", text: String);
+//!
+//!     state.push(Document { text: "one".to_owned() });
+//!     state.set_global("doc");
+//!
+//!     state.do_string("doc.text = 'two' \
+//!                      local snap = doc:snapshot() \
+//!                      doc.text = 'three' \
+//!                      doc:restore(snap)");
+//!     state.get_global("doc");
+//!     assert_eq!(state.to_type::<Document>(-1), Some(Document { text: "two".to_owned() }));
+//! }
+//! ```
+//!
+//! ## Map structs to tables
+//!
+//! Structs declared by `lua_struct` are pushed as tables with fields named as the
+//! fields of the struct:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_struct!(#[derive(Debug, PartialEq)] Config { retries: Integer, host: String, verbose: bool });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     let config = Config { retries: 3, host: "localhost".to_owned(), verbose: true };
+//!     state.push(config);
+//!     state.set_global("config");
+//!
+//!     state.do_string("return config.retries, config.host, config.verbose");
+//!     assert_eq!(state.to_type::<Integer>(-3), Some(3));
+//!     assert_eq!(state.to_type::<String>(-2).unwrap(), "localhost");
+//!     assert_eq!(state.to_bool(-1), true);
+//!
+//!     state.do_string("return config");
+//!     let restored = state.to_type::<Config>(-1).unwrap();
+//!     assert_eq!(restored, Config { retries: 3, host: "localhost".to_owned(), verbose: true });
+//!
+//!     state.do_string("return {retries = 3, host = 'localhost'}");
+//!     assert!(state.to_type::<Config>(-1).is_none());
+//!
+//!     state.do_string("return {retries = 'three', host = 'localhost', verbose = false}");
+//!     assert!(state.to_type::<Config>(-1).is_none());
+//! }
+//! ```
+//!
+//! ## Defaults of fields
+//!
+//! Fields of `lua_struct` could have defaults which are used if a table doesn't have them:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_struct!(Opts { timeout: Integer = 30, name: String, tags: String = "none".to_owned() });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return {name = 'job', tags = 'fast'}");
+//!     let opts = state.to_type::<Opts>(-1).unwrap();
+//!     assert_eq!(opts.timeout, 30);
+//!     assert_eq!(opts.name, "job");
+//!     assert_eq!(opts.tags, "fast");
+//!
+//!     state.do_string("return {timeout = 5, name = 'job'}");
+//!     let opts = state.to_type::<Opts>(-1).unwrap();
+//!     assert_eq!(opts.timeout, 5);
+//!     assert_eq!(opts.tags, "none");
+//!
+//!     state.do_string("return {timeout = 5}");
+//!     assert!(state.to_type::<Opts>(-1).is_none());
+//! }
+//! ```
+//!
+//! ## Return pages of data
+//!
+//! Macro `lua_paged_fn` declares a function which takes offset and limit and returns
+//! only that page of a vector kept by `set_context`:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_array_type!(Page<Integer>);
+//!
+//! lua_paged_fn!(fn query -> Integer, "numbers");
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     let numbers: Vec<Integer> = (0..100).collect();
+//!     lua_macros::set_context(&mut state, "numbers", numbers);
+//!     state.push_fn(Some(query));
+//!     state.set_global("query");
+//!
+//!     state.do_string("return query(0, 3)");
+//!     assert_eq!(state.to_type::<Page>(-1).unwrap().0, vec![0, 1, 2]);
+//!
+//!     state.do_string("return query(3, 3)");
+//!     assert_eq!(state.to_type::<Page>(-1).unwrap().0, vec![3, 4, 5]);
+//!
+//!     state.do_string("return query(98, 10)");
+//!     assert_eq!(state.to_type::<Page>(-1).unwrap().0, vec![98, 99]);
+//!
+//!     assert!(state.do_string("return query(-1, 10)").is_err());
+//! }
+//! ```
+//!
+//! ## Reject NaN and infinities
+//!
+//! Slot `finite Number` of `convert_arguments` accepts finite numbers only:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Number};
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! unsafe extern "C" fn sqrt(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     match convert_arguments!(state, finite Number) {
+//!         Ok((value,)) => {
+//!             state.push(value.sqrt());
+//!             1
+//!         },
+//!         Err(pos) => state.arg_error(pos, "finite number expected"),
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(sqrt));
+//!     state.set_global("sqrt");
+//!
+//!     assert!(!state.do_string("return sqrt(2.25)").is_err());
+//!     assert_eq!(state.to_type::<Number>(-1), Some(1.5));
+//!
+//!     assert!(state.do_string("return sqrt(0/0)").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #1"));
+//!     assert!(state.do_string("return sqrt(1/0)").is_err());
+//!     assert!(state.do_string("return sqrt(-1/0)").is_err());
+//! }
+//! ```
+//!
+//! ## Read string tags with `lua_enum`
+//!
+//! Values of `lua_enum` could be strings, then variants are read from and pushed as
+//! string tags without any userdata:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! #[derive(Debug, PartialEq)]
+//! enum Level {
+//!     Low,
+//!     Medium,
+//!     High,
+//! }
+//!
+//! lua_enum!(Level { Low => "low", Medium => "medium", High => "high" });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return 'medium'");
+//!     assert_eq!(state.to_type::<Level>(-1), Some(Level::Medium));
+//!
+//!     state.do_string("return 'extreme'");
+//!     assert_eq!(state.to_type::<Level>(-1), None);
+//!
+//!     state.push(Level::High);
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "high");
+//!     assert_eq!(state.to_type::<Level>(-1), Some(Level::High));
+//! }
+//! ```
+//!
+//! ## Push nested tables
+//!
+//! Macro `push_table` pushes a table built of pairs or items. Nested calls make
+//! values of fields:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!
+//!     push_table!(state, {
+//!         "name" => "bob",
+//!         "age" => 30,
+//!         "tags" => push_table!(state, [1, 2, 3]),
+//!         1 => push_table!(state, { "nested" => true }),
+//!     });
+//!     assert_eq!(state.get_top(), 1);
+//!     state.set_global("user");
+//!
+//!     state.do_string("return user.name, user.age, table.concat(user.tags, ','), user[1].nested");
+//!     assert_eq!(state.to_type::<String>(-4).unwrap(), "bob");
+//!     assert_eq!(state.to_type::<Integer>(-3), Some(30));
+//!     assert_eq!(state.to_type::<String>(-2).unwrap(), "1,2,3");
+//!     assert_eq!(state.to_bool(-1), true);
+//! }
+//! ```
+//!
+//! ## List all variants of enums
+//!
+//! Flag `all` of `lua_enum` adds array `all` with values of variants in order of
+//! declaration:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! #[derive(Debug, PartialEq)]
+//! enum Direction {
+//!     North,
+//!     East,
+//!     South,
+//!     West,
+//! }
+//!
+//! lua_enum!(Direction { North => "n", East => "e", South => "s", West => "w" }, all);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     Direction::attach(&mut state);
+//!
+//!     state.do_string("local names = {} \
+//!                      for _, v in ipairs(Direction.all) do names[#names + 1] = v end \
+//!                      return #names, table.concat(names, ',')");
+//!     assert_eq!(state.to_type::<i64>(-2), Some(4));
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "n,e,s,w");
+//! }
+//! ```
+//!
+//! ## Add context to errors
+//!
+//! Macro `with_error_context` prepends a context to the message of an error, so hosts
+//! with many scripts could tell where it comes from:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     let status = with_error_context!(state, "plugin=foo", {
+//!         state.do_string("error('oops', 0)")
+//!     });
+//!     assert!(status.is_err());
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "plugin=foo: oops");
+//!
+//!     let status = with_error_context!(state, "plugin=foo", {
+//!         state.do_string("return 1")
+//!     });
+//!     assert!(!status.is_err());
+//!     assert_eq!(state.to_type::<i64>(-1), Some(1));
+//! }
+//! ```
+//!
+//! ## Declare functions with typed arguments
+//!
+//! Macro `lua_function` declares the whole `extern "C"` function. Arguments are
+//! converted before the body and the result is pushed back:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_function!(fn add(a: Integer, b: Integer) -> Integer {
+//!     a + b
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(add));
+//!     state.set_global("add");
+//!
+//!     state.do_string("return add(1, 2)");
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(3));
+//!
+//!     assert!(state.do_string("return add(1, 'x')").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #2"));
+//!     assert!(state.to_str(-1).unwrap().contains("Integer expected"));
+//!
+//!     assert!(state.do_string("return add(1, 2, 3)").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #3"));
+//! }
+//! ```
+//!
+//! ## Poll async operations from scripts
+//!
+//! Macro `lua_async_handle_fn` declares a function which returns a handle of a future.
+//! Scripts call `handle:poll()` which returns `nil` until the output is ready:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use std::future::Future;
+//! # use std::pin::Pin;
+//! # use std::task::{Context, Poll};
+//!
+//! // Becomes ready on the third poll
+//! struct Countdown(u8, String);
+//!
+//! impl Future for Countdown {
+//!     type Output = String;
+//!
+//!     fn poll(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<String> {
+//!         if self.0 == 0 {
+//!             Poll::Ready(self.1.clone())
+//!         } else {
+//!             self.0 -= 1;
+//!             Poll::Pending
+//!         }
+//!     }
+//! }
+//!
+//! lua_async_handle_fn!(fn start_fetch(url: String) -> String {
+//!     Countdown(2, format!("content of {}", url))
+//! });
+//!
+//! lua_async_handle_fn!(fn ready() -> i64 {
+//!     ::std::future::ready(42)
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(start_fetch));
+//!     state.set_global("start_fetch");
+//!     state.push_fn(Some(ready));
+//!     state.set_global("ready");
+//!
+//!     state.do_string("local handle = start_fetch('site') \
+//!                      local polls, result = 0, nil \
+//!                      repeat polls = polls + 1 result = handle:poll() until result \
+//!                      return polls, result");
+//!     assert_eq!(state.to_type::<i64>(-2), Some(3));
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "content of site");
+//!
+//!     state.do_string("return ready():poll()");
+//!     assert_eq!(state.to_type::<i64>(-1), Some(42));
+//!
+//!     assert!(state.do_string("local handle = ready() handle:poll() handle:poll()").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("finished already"));
+//! }
+//! ```
+//!
+//! ## Return multiple values
+//!
+//! Functions declared by `lua_function` return tuples as multiple values:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_function!(fn divmod(a: Integer, b: Integer) -> (Integer, Integer) {
+//!     (a / b, a % b)
+//! });
+//!
+//! lua_function!(fn nothing() -> () {
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     state.push_fn(Some(divmod));
+//!     state.set_global("divmod");
+//!     state.push_fn(Some(nothing));
+//!     state.set_global("nothing");
+//!
+//!     state.do_string("local q, r = divmod(7, 2) return q, r");
+//!     assert_eq!(state.to_type::<Integer>(-2), Some(3));
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(1));
+//!
+//!     state.do_string("return select('#', nothing())");
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(0));
+//! }
+//! ```
+//!
+//! ## Validate values of arguments
+//!
+//! Macro `lua_validated_fn` checks constraints of arguments besides their types:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//!
+//! lua_validated_fn!(fn create { name: string(min_len = 1), age: integer(0..=150) } -> String {
+//!     format!("{} ({})", name, age)
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(create));
+//!     state.set_global("create");
+//!
+//!     state.do_string("return create('bob', 30)");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "bob (30)");
+//!
+//!     assert!(state.do_string("return create('', 30)").is_err());
+//!     let msg = state.to_type::<String>(-1).unwrap();
+//!     assert!(msg.contains("bad argument #1"));
+//!     assert!(msg.contains("`name` violates min_len = 1"));
+//!
+//!     assert!(state.do_string("return create('bob', 200)").is_err());
+//!     let msg = state.to_type::<String>(-1).unwrap();
+//!     assert!(msg.contains("bad argument #2"));
+//!     assert!(msg.contains("`age` violates range 0..=150"));
+//!
+//!     assert!(state.do_string("return create('bob', '30')").is_err());
+//!     assert!(state.to_type::<String>(-1).unwrap().contains("`age` must be integer"));
+//! }
+//! ```
+//!
+//! ## Call functions of scripts
+//!
+//! Macro `lua_call` calls a global function in protected mode and converts its results:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.do_string("function format(fmt, a, b) return fmt .. ' ' .. a .. ' ' .. b end \
+//!                      function divmod(a, b) return a // b, a % b end \
+//!                      function fail() error('oops', 0) end");
+//!
+//!     let fmt = "values:".to_owned();
+//!     let text = lua_call!(state, "format", (fmt, 1, 2) -> String);
+//!     assert_eq!(text, Ok("values: 1 2".to_owned()));
+//!
+//!     let results = lua_call!(state, "divmod", (7, 2) -> (Integer, Integer));
+//!     assert_eq!(results, Ok((3, 1)));
+//!
+//!     let error = lua_call!(state, "fail", () -> Integer);
+//!     assert_eq!(error, Err("oops".to_owned()));
+//!     assert_eq!(state.get_top(), 0);
+//! }
+//! ```
+//!
+//! ## Flatten nested tables to structs
+//!
+//! Fields of `lua_struct` declared as `name from "a.b": Type` are read from nested
+//! tables by dotted paths:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_struct!(Conf {
+//!     db_host from "db.host": String,
+//!     db_port from "db.port": Integer = 5432,
+//!     name: String,
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return {name = 'app', db = {host = 'localhost', port = 6543}}");
+//!     let conf = state.to_type::<Conf>(-1).unwrap();
+//!     assert_eq!(conf.db_host, "localhost");
+//!     assert_eq!(conf.db_port, 6543);
+//!     assert_eq!(conf.name, "app");
+//!
+//!     state.do_string("return {name = 'app', db = {host = 'localhost'}}");
+//!     assert_eq!(state.to_type::<Conf>(-1).unwrap().db_port, 5432);
+//!
+//!     state.do_string("return {name = 'app', db = 'localhost'}");
+//!     assert!(state.to_type::<Conf>(-1).is_none());
+//!
+//!     state.push(conf);
+//!     state.set_global("conf");
+//!     state.do_string("return conf.db.host");
+//!     assert_eq!(state.to_type::<String>(-1).unwrap(), "localhost");
+//! }
+//! ```
+//!
+//! ## Catch errors of Lua in Rust
+//!
+//! Macro `pcall_scope` runs a block in protected mode. Errors raised inside (even by
+//! API calls which jump out like `error`) become `Err` with the message:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push(1);
+//!
+//!     let sum = pcall_scope!(state, {
+//!         state.do_string("return 20 + 22");
+//!         state.to_type::<Integer>(-1)
+//!     });
+//!     assert_eq!(sum, Ok(Some(42)));
+//!
+//!     let failed = pcall_scope!(state, {
+//!         state.push_string("oops");
+//!         state.error();
+//!     });
+//!     assert_eq!(failed, Err("oops".to_owned()));
+//!     assert_eq!(state.get_top(), 1);
+//! }
+//! ```
+//!
+//! ## Cache results of functions
+//!
+//! Macro `lua_memoized_fn` declares a function which runs its body once for every
+//! set of arguments:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//! # use std::cell::Cell;
+//!
+//! thread_local! {
+//!     static CALLS: Cell<u32> = Cell::new(0);
+//! }
+//!
+//! lua_memoized_fn!(fn compute(n: Integer) -> Integer {
+//!     CALLS.with(|calls| calls.set(calls.get() + 1));
+//!     (1..n + 1).product()
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(compute));
+//!     state.set_global("compute");
+//!
+//!     state.do_string("return compute(5) + compute(5)");
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(240));
+//!     assert_eq!(CALLS.with(Cell::get), 1);
+//!
+//!     state.do_string("return compute(6)");
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(720));
+//!     assert_eq!(CALLS.with(Cell::get), 2);
+//! }
+//! ```
+//!
+//! ## Read `nil` to `None`
+//!
+//! Slot `Option<Type>` of `convert_arguments` reads `nil` as `None`, but the argument is
+//! still counted, unlike `?Type` which allows to omit trailing arguments. A value of
+//! other type is an error:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return nil, 'a'");
+//!     let args = convert_arguments!(state, Option<Integer>, String);
+//!     assert_eq!(args, Ok((None, "a".to_owned())));
+//!
+//!     state.set_top(0);
+//!     state.do_string("return 5, 'a'");
+//!     let args = convert_arguments!(state, Option<Integer>, String);
+//!     assert_eq!(args, Ok((Some(5), "a".to_owned())));
+//!
+//!     state.set_top(0);
+//!     state.do_string("return {}, 'a'");
+//!     let args = convert_arguments!(state, Option<Integer>, String);
+//!     assert_eq!(args, Err(1));
+//!
+//!     let args = convert_arguments!(@typed state, Option<Integer>, String);
+//!     assert_eq!(args.unwrap_err().reason(), "integer or nil expected, got table");
+//! }
+//! ```
+//!
+//! ## Read binary strings
+//!
+//! Type `Bytes` keeps all bytes of a Lua's string, even if they are not UTF-8 or
+//! contain zeros:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use lua_macros::Bytes;
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!
+//!     state.do_string("return 'a\\0b\\255'");
+//!     let (bytes,) = convert_arguments!(state, Bytes).unwrap();
+//!     assert_eq!(bytes, Bytes(vec![b'a', 0, b'b', 255]));
+//!
+//!     state.push(Bytes(vec![0, 1, 2]));
+//!     state.set_global("blob");
+//!     state.do_string("return #blob, blob:byte(3)");
+//!     assert_eq!(state.to_type::<i64>(-2), Some(3));
+//!     assert_eq!(state.to_type::<i64>(-1), Some(2));
+//! }
+//! ```
+//!
+//! ## Send events to channels
+//!
+//! Macro `lua_channel_sender` sets a global function which sends events with payloads
+//! to a channel of the host:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use lua_macros::LuaValue;
+//! # use std::sync::mpsc;
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     let (tx, rx) = mpsc::channel();
+//!     lua_channel_sender!(state, "emit", tx);
+//!
+//!     state.do_string("emit('saved', {id = 7}) emit('closed')");
+//!     let (event, payload) = rx.try_recv().unwrap();
+//!     assert_eq!(event, "saved");
+//!     let id = (LuaValue::String("id".to_owned()), LuaValue::Integer(7));
+//!     assert_eq!(payload, LuaValue::Table(vec![id]));
+//!     assert_eq!(rx.try_recv().unwrap(), ("closed".to_owned(), LuaValue::Nil));
+//!
+//!     drop(rx);
+//!     assert!(state.do_string("emit('lost')").is_err());
+//! }
+//! ```
+//!
+//! ## Read arrays of fixed length
+//!
+//! Macro `lua_fixed_array_type` reads an array with exact count of items:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Number};
+//!
+//! lua_fixed_array_type!(Color<Number; 4>);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return {1, 0.5, 0, 1}");
+//!     let Color(rgba) = state.to_type::<Color>(-1).unwrap();
+//!     assert_eq!(rgba, [1.0, 0.5, 0.0, 1.0]);
+//!
+//!     state.do_string("return {1, 0.5, 0}");
+//!     assert!(state.to_type::<Color>(-1).is_none());
+//!
+//!     state.do_string("return {1, 0.5, 0, 1, 1}");
+//!     assert!(state.to_type::<Color>(-1).is_none());
+//!
+//!     state.push(Color(rgba));
+//!     state.set_global("color");
+//!     state.do_string("return #color, color[2]");
+//!     assert_eq!(state.to_type::<i64>(-2), Some(4));
+//!     assert_eq!(state.to_type::<Number>(-1), Some(0.5));
+//! }
+//! ```
+//!
+//! ## Coerce strings to numbers
+//!
+//! Slots of types `Integer` and `Number` reject strings, also in functions declared
+//! by other macros, but `@coerce` form accepts numeric strings as Lua's arithmetic does:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer, Number};
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! pub unsafe extern "C" fn strict_add(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     match convert_arguments!(state, Integer, Number) {
+//!         Ok((a, b)) => state.push_number(a as Number + b),
+//!         Err(pos) => return state.arg_error(pos, "number expected"),
+//!     }
+//!     1
+//! }
+//!
+//! pub unsafe extern "C" fn loose_add(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     match convert_arguments!(@coerce state, Integer, Number) {
+//!         Ok((a, b)) => state.push_number(a as Number + b),
+//!         Err(pos) => return state.arg_error(pos, "number expected"),
+//!     }
+//!     1
+//! }
+//!
+//! lua_function!(fn sum(a: Integer, b: Integer) -> Integer {
+//!     a + b
+//! });
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(sum));
+//!     state.set_global("sum");
+//!     state.push_fn(Some(strict_add));
+//!     state.set_global("strict_add");
+//!     state.push_fn(Some(loose_add));
+//!     state.set_global("loose_add");
+//!
+//!     assert!(!state.do_string("return strict_add(2, 0.5)").is_err());
+//!     assert_eq!(state.to_type::<Number>(-1), Some(2.5));
+//!
+//!     assert!(state.do_string("return strict_add('2', 0.5)").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #1"));
+//!
+//!     assert!(state.do_string("return strict_add(2, '0.5')").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #2"));
+//!
+//!     assert!(!state.do_string("return loose_add('2', '0.5')").is_err());
+//!     assert_eq!(state.to_type::<Number>(-1), Some(2.5));
+//!
+//!     assert!(state.do_string("return loose_add('two', 0.5)").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #1"));
+//!
+//!     assert!(!state.do_string("return sum(2, 3)").is_err());
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(5));
+//!
+//!     assert!(state.do_string("return sum('2', 3)").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #1"));
+//!
+//!     assert!(state.do_string("return sum(2, '3')").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #2"));
+//! }
+//! ```
+//!
+//! ## Convert arguments to structs
+//!
+//! Arguments are read to fields of a declared struct in order of fields:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//!
+//! struct RepeatArgs {
+//!     text: String,
+//!     count: Integer,
+//!     sep: Option<String>,
+//! }
+//!
+//! pub unsafe extern "C" fn repeat(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let args = convert_arguments!(state => RepeatArgs {
+//!         text: String,
+//!         count: Integer,
+//!         sep: ?String,
+//!     });
+//!     match args {
+//!         Ok(args) => {
+//!             let sep = args.sep.unwrap_or_default();
+//!             let parts = vec![args.text; args.count as usize];
+//!             state.push_string(&parts.join(&sep));
+//!             1
+//!         },
+//!         Err(pos) => state.arg_error(pos, "wrong argument"),
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.push_fn(Some(repeat));
+//!     state.set_global("rep");
+//!
+//!     assert!(!state.do_string("return rep('ab', 3, '-')").is_err());
+//!     assert_eq!(state.to_str(-1), Some("ab-ab-ab"));
+//!
+//!     assert!(!state.do_string("return rep('ab', 2)").is_err());
+//!     assert_eq!(state.to_str(-1), Some("abab"));
+//!
+//!     assert!(state.do_string("return rep('ab', {})").is_err());
+//!     assert!(state.to_str(-1).unwrap().contains("bad argument #2"));
+//! }
+//! ```
+//!
+//! ## Read tables of arrays
+//!
+//! Macro `lua_multimap_type` reads a table which values are arrays:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//!
+//! lua_multimap_type!(Groups<String, Integer>);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     state.do_string("return { evens = {2, 4}, odds = {1, 3, 5}, none = {} }");
+//!     let Groups(groups) = state.to_type::<Groups>(-1).unwrap();
+//!     assert_eq!(groups["evens"], vec![2, 4]);
+//!     assert_eq!(groups["odds"], vec![1, 3, 5]);
+//!     assert!(groups["none"].is_empty());
+//!
+//!     state.do_string("return { evens = {2, 'four'} }");
+//!     assert!(state.to_type::<Groups>(-1).is_none());
+//!
+//!     state.do_string("return { evens = 2 }");
+//!     assert!(state.to_type::<Groups>(-1).is_none());
+//!
+//!     state.push(Groups(groups));
+//!     state.set_global("groups");
+//!     state.do_string("return #groups.odds, groups.evens[2]");
+//!     assert_eq!(state.to_type::<Integer>(-2), Some(3));
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(4));
+//! }
+//! ```
+//!
+//! ## Share userdata by reference
+//!
+//! Macro `lua_shared_userdata` keeps `Rc<RefCell<T>>` in userdata, so every
+//! reference sees changes of the value:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//! # use std::cell::RefCell;
+//! # use std::rc::Rc;
+//!
+//! pub struct Counter {
+//!     count: Integer,
+//! }
+//!
+//! unsafe extern "C" fn inc(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     match Counter::get_shared(&mut state, 1) {
+//!         Some(counter) => {
+//!             counter.borrow_mut().count += 1;
+//!             0
+//!         },
+//!         None => state.arg_error(1, "`Counter` expected"),
+//!     }
+//! }
+//!
+//! unsafe extern "C" fn get(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     match Counter::get_shared(&mut state, 1) {
+//!         Some(counter) => {
+//!             state.push_integer(counter.borrow().count);
+//!             1
+//!         },
+//!         None => state.arg_error(1, "`Counter` expected"),
+//!     }
+//! }
+//!
+//! lua_shared_userdata!(Counter, "inc" => inc, "get" => get);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     Counter::attach(&mut state);
+//!
+//!     let counter = Rc::new(RefCell::new(Counter { count: 0 }));
+//!     Counter::push_shared(&mut state, counter.clone());
+//!     state.set_global("a");
+//!     Counter::push_shared(&mut state, counter.clone());
+//!     state.set_global("b");
+//!
+//!     state.do_string("a:inc() a:inc() b:inc() return b:get()");
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(3));
+//!     assert_eq!(counter.borrow().count, 3);
+//!
+//!     state.get_global("a");
+//!     let same = Counter::get_shared(&mut state, -1).unwrap();
+//!     assert!(Rc::ptr_eq(&same, &counter));
+//!
+//!     // Closing of the state drops references of userdata
+//!     drop(same);
+//!     drop(state);
+//!     assert_eq!(Rc::strong_count(&counter), 1);
+//! }
+//! ```
+//!
+//! ## Move values to userdata
+//!
+//! Mode `move` of `lua_userdata` moves values which aren't `Clone` to Lua:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::State;
+//! # use lua_macros::lua::ffi::lua_State;
+//! # use lua_macros::lua::libc::c_int;
+//! # use std::cell::Cell;
+//! # use std::rc::Rc;
+//!
+//! pub struct Conn {
+//!     sent: Vec<String>,
+//!     closed: Rc<Cell<bool>>,
+//! }
+//!
+//! impl Drop for Conn {
+//!     fn drop(&mut self) {
+//!         self.closed.set(true);
+//!     }
+//! }
+//!
+//! unsafe extern "C" fn send(ls: *mut lua_State) -> c_int {
+//!     let mut state = State::from_ptr(ls);
+//!     let msg = match state.to_type::<String>(2) {
+//!         Some(msg) => msg,
+//!         None => return state.arg_error(2, "string expected"),
+//!     };
+//!     match Conn::get_ref(&mut state, 1) {
+//!         Some(conn) => conn.sent.push(msg),
+//!         None => return state.arg_error(1, "`Conn` expected"),
+//!     }
+//!     0
+//! }
+//!
+//! lua_userdata!(Conn, move, "send" => send);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!     state.open_libs();
+//!     Conn::attach(&mut state);
+//!
+//!     let closed = Rc::new(Cell::new(false));
+//!     let conn = Conn { sent: Vec::new(), closed: closed.clone() };
+//!     conn.push_owned(&mut state);
+//!     state.set_global("conn");
+//!
+//!     state.do_string("conn:send('hello') conn:send('bye')");
+//!     state.get_global("conn");
+//!     assert_eq!(Conn::get_ref(&mut state, -1).unwrap().sent, vec!["hello", "bye"]);
+//!     state.pop(1);
+//!
+//!     state.do_string("conn = nil collectgarbage()");
+//!     assert!(closed.get());
+//! }
+//! ```
+//!
+//! ## Push `HashMap` to Lua
+//!
+//! Wrappers of `lua_table_type` are pushed back as tables:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate lua_macros;
+//! # use lua_macros::lua::{State, Integer};
+//! # use std::collections::HashMap;
+//!
+//! lua_table_type!(Scores<String, Integer>);
+//!
+//! fn main() {
+//!     let mut state = State::new();
+//!
+//!     let mut scores = HashMap::new();
+//!     scores.insert("alice".to_owned(), 3);
+//!     scores.insert("bob".to_owned(), 5);
+//!     state.push(Scores(scores.clone()));
+//!     state.set_global("scores");
+//!
+//!     state.do_string("return scores.alice + scores.bob");
+//!     assert_eq!(state.to_type::<Integer>(-1), Some(8));
+//!
+//!     state.get_global("scores");
+//!     let Scores(copy) = state.to_type(-1).unwrap();
+//!     assert_eq!(copy, scores);
+//! }
+//! ```
+
+
+pub extern crate lua;
+
+#[cfg(feature = "json")]
+extern crate serde_json;
+#[cfg(feature = "bitset")]
+pub extern crate bitvec;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "log")]
+pub extern crate log;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use std::time::Instant;
+use lua::{State, Index, Integer, Number, FromLua, ToLua, Reference, ThreadStatus, Type, MULTRET, REGISTRYINDEX};
+use lua::ffi::{lua_State, lua_newstate, lua_pushlstring, lua_tolstring, luaL_argerror};
+use lua::libc::{c_int, c_void, size_t, free, realloc};
+
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "serde")]
+pub mod de;
+#[cfg(feature = "serde")]
+pub mod ser;
+
+thread_local! {
+    static TRACE_SINK: RefCell<Option<Box<dyn FnMut(&str)>>> = RefCell::new(None);
+}
+
+/// Sets sink for messages of `trace_access` flag of `lua_userdata` in the current thread.
+///
+/// Messages are printed to stderr if sink is not set.
+pub fn set_trace_sink<F: FnMut(&str) + 'static>(sink: F) {
+    TRACE_SINK.with(|cell| *cell.borrow_mut() = Some(Box::new(sink)));
+}
+
+#[doc(hidden)]
+pub fn trace_access(message: &str) {
+    TRACE_SINK.with(|cell| {
+        match *cell.borrow_mut() {
+            Some(ref mut sink) => sink(message),
+            None => eprintln!("{}", message),
+        }
+    });
+}
+
+/// Pushes value of key at index 2 taken by previous `__index` handler kept at `prev`.
+///
+/// The handler could be a function or a table, `nil` is pushed if there is no handler.
+#[doc(hidden)]
+pub fn call_prev_index(state: &mut State, prev: Index) {
+    if state.is_fn(prev) {
+        state.push_value(prev);
+        state.push_value(1);
+        state.push_value(2);
+        state.call(2, 1);
+    } else if state.is_table(prev) {
+        state.push_value(2);
+        state.get_table(prev);
+    } else {
+        state.push_nil();
+    }
+}
+
+/// Sets key at index 2 to value at index 3 by previous `__newindex` handler kept at `prev`.
+///
+/// Returns `false` if there is no handler.
+#[doc(hidden)]
+pub fn call_prev_newindex(state: &mut State, prev: Index) -> bool {
+    if state.is_fn(prev) {
+        state.push_value(prev);
+        state.push_value(1);
+        state.push_value(2);
+        state.push_value(3);
+        state.call(3, 0);
+    } else if state.is_table(prev) {
+        state.push_value(2);
+        state.push_value(3);
+        state.set_table(prev);
+    } else {
+        return false;
+    }
+    true
+}
+
+/// Builder of table which is placed on top of the stack.
+///
+/// Use `table_builder` macro to create it.
+pub struct TableBuilder<'a> {
+    state: &'a mut State,
+    len: Integer,
+}
+
+impl<'a> TableBuilder<'a> {
+    /// Pushes a new empty table to the stack.
+    pub fn new(state: &'a mut State) -> Self {
+        state.new_table();
+        TableBuilder {
+            state: state,
+            len: 0,
+        }
+    }
+
+    /// Sets field of the table.
+    pub fn field<T: ToLua>(mut self, name: &str, value: T) -> Self {
+        value.to_lua(self.state);
+        self.state.set_field(-2, name);
+        self
+    }
+
+    /// Appends item to array part of the table.
+    pub fn item<T: ToLua>(mut self, value: T) -> Self {
+        self.len += 1; // Starts from 1
+        value.to_lua(self.state);
+        self.state.raw_seti(-2, self.len);
+        self
+    }
+
+    /// Finishes building and leaves the table on top of the stack.
+    pub fn build(self) {
+    }
+}
+
+/// Value which is on top of the stack already, pushing it does nothing.
+///
+/// It's returned by `push_table`, so nested tables could be values of fields.
+pub struct Pushed;
+
+impl ToLua for Pushed {
+    fn to_lua(&self, _state: &mut State) {
+    }
+}
+
+/// Pushes value at dotted `path` of table at `index`.
+///
+/// Pushes `nil` if a part of the path is missing or isn't a table.
+pub fn get_path(state: &mut State, index: Index, path: &str) {
+    let index = state.abs_index(index);
+    state.push_value(index);
+    for key in path.split('.') {
+        if !state.is_table(-1) {
+            state.pop(1);
+            state.push_nil();
+            break;
+        }
+        state.get_field(-1, key);
+        state.remove(-2);
+    }
+}
+
+/// Pops value and sets it at dotted `path` of table at `index`.
+///
+/// Missing tables of the path are created.
+pub fn set_path(state: &mut State, index: Index, path: &str) {
+    let index = state.abs_index(index);
+    let value = state.get_top();
+    state.push_value(index);
+    let mut keys = path.split('.').peekable();
+    while let Some(key) = keys.next() {
+        if keys.peek().is_some() {
+            state.get_field(-1, key);
+            if !state.is_table(-1) {
+                state.pop(1);
+                state.new_table();
+                state.push_value(-1);
+                state.set_field(-3, key);
+            }
+            state.remove(-2);
+        } else {
+            state.push_value(value);
+            state.set_field(-2, key);
+        }
+    }
+    state.pop(2); // Pop the last table and the value
+}
+
+/// Values returned by Lua function, see `lua_function`.
+pub trait LuaResults {
+    /// Pushes values in order and returns their count.
+    fn push_results(self, state: &mut State) -> c_int;
+}
+
+impl LuaResults for () {
+    fn push_results(self, _state: &mut State) -> c_int {
+        0
+    }
+}
+
+macro_rules! lua_results_tuple {
+    ($( $var:ident ),+) => {
+        impl<$( $var: ToLua ),+> LuaResults for ($( $var, )+) {
+            #[allow(non_snake_case)]
+            fn push_results(self, state: &mut State) -> c_int {
+                let ($( $var, )+) = self;
+                let mut count = 0;
+                $(
+                state.push($var);
+                count += 1;
+                )+
+                count
+            }
+        }
+    };
+}
+
+lua_results_tuple!(A);
+lua_results_tuple!(A, B);
+lua_results_tuple!(A, B, C);
+lua_results_tuple!(A, B, C, D);
+lua_results_tuple!(A, B, C, D, E);
+lua_results_tuple!(A, B, C, D, E, F);
+
+/// Bytes of Lua's string, which could be not UTF-8 and contain zeros.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Bytes(pub Vec<u8>);
+
+impl FromLua for Bytes {
+    fn from_lua(state: &mut State, index: Index) -> Option<Self> {
+        // Numbers are not converted, because `lua_tolstring` changes them in place
+        if state.type_of(index) != Some(Type::String) {
+            return None;
+        }
+        let mut len: size_t = 0;
+        unsafe {
+            let ptr = lua_tolstring(state.as_ptr(), index, &mut len);
+            if ptr.is_null() {
+                return None;
+            }
+            let bytes = ::std::slice::from_raw_parts(ptr as *const u8, len);
+            Some(Bytes(bytes.to_vec()))
+        }
+    }
+}
+
+impl ToLua for Bytes {
+    fn to_lua(&self, state: &mut State) {
+        unsafe {
+            lua_pushlstring(state.as_ptr(), self.0.as_ptr() as *const _, self.0.len());
+        }
+    }
+}
+
+/// Fraction in range `0.0..=1.0`.
+///
+/// Use `percent` or `percent(100)` slots of `convert_arguments` to read it from
+/// numbers in range `0..=1` or `0..=100`.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct Percent(pub Number);
+
+impl Percent {
+    /// Makes fraction from value in range `0..=scale`, scale has to be positive.
+    pub fn from_scaled(value: Number, scale: Number) -> Option<Self> {
+        if scale > 0.0 && value >= 0.0 && value <= scale {
+            Some(Percent(value / scale))
+        } else {
+            None
+        }
+    }
+}
+
+impl FromLua for Percent {
+    fn from_lua(state: &mut State, index: Index) -> Option<Self> {
+        // Numeric strings are rejected like by strict number slots
+        if state.type_of(index) != Some(Type::Number) {
+            return None;
+        }
+        state.to_type::<Number>(index).and_then(|value| Percent::from_scaled(value, 1.0))
+    }
+}
+
+impl ToLua for Percent {
+    fn to_lua(&self, state: &mut State) {
+        state.push_number(self.0);
+    }
+}
+
+/// Owned copy of Lua's value which could be kept at side of Rust.
+///
+/// Functions, userdata, threads and tables which contain themselves can't be copied.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LuaValue {
+    Nil,
+    Boolean(bool),
+    Integer(Integer),
+    Number(Number),
+    String(String),
+    /// Pairs of table in order of iteration.
+    Table(Vec<(LuaValue, LuaValue)>),
+}
+
+impl LuaValue {
+    fn read(state: &mut State, index: Index, visited: &mut Vec<*const c_void>) -> Option<Self> {
+        let index = state.abs_index(index);
+        match state.type_of(index) {
+            None | Some(Type::None) | Some(Type::Nil) => Some(LuaValue::Nil),
+            Some(Type::Boolean) => Some(LuaValue::Boolean(state.to_bool(index))),
+            Some(Type::Number) => {
+                if state.is_integer(index) {
+                    Some(LuaValue::Integer(state.to_integer(index)))
+                } else {
+                    Some(LuaValue::Number(state.to_number(index)))
+                }
+            },
+            Some(Type::String) => state.to_str(index).map(|s| LuaValue::String(s.to_owned())),
+            Some(Type::Table) => {
+                let pointer = state.to_pointer(index);
+                if visited.contains(&pointer) {
+                    return None;
+                }
+                visited.push(pointer);
+                let mut pairs = Vec::new();
+                state.push_nil();
+                while state.next(index) {
+                    let pair = LuaValue::read(state, -2, visited)
+                        .and_then(|key| LuaValue::read(state, -1, visited).map(|value| (key, value)));
+                    match pair {
+                        Some(pair) => {
+                            pairs.push(pair);
+                            state.pop(1); // Pop `value` only
+                        },
+                        None => {
+                            state.pop(2);
+                            return None;
+                        },
+                    }
+                }
+                visited.pop();
+                Some(LuaValue::Table(pairs))
+            },
+            Some(_) => None,
+        }
+    }
+}
+
+impl FromLua for LuaValue {
+    fn from_lua(state: &mut State, index: Index) -> Option<Self> {
+        let mut visited = Vec::new();
+        LuaValue::read(state, index, &mut visited)
+    }
+}
+
+impl ToLua for LuaValue {
+    fn to_lua(&self, state: &mut State) {
+        match *self {
+            LuaValue::Nil => state.push_nil(),
+            LuaValue::Boolean(b) => state.push_bool(b),
+            LuaValue::Integer(i) => state.push_integer(i),
+            LuaValue::Number(n) => state.push_number(n),
+            LuaValue::String(ref s) => {
+                state.push_string(s);
+            },
+            LuaValue::Table(ref pairs) => {
+                state.create_table(0, pairs.len() as c_int);
+                for &(ref key, ref value) in pairs {
+                    key.to_lua(state);
+                    value.to_lua(state);
+                    state.raw_set(-3);
+                }
+            },
+        }
+    }
+}
+
+/// User-defined conversion which is used by `push_codec` and `read_codec` macros.
+///
+/// Unlike `ToLua` and `FromLua` could have own representation for types of other crates.
+pub trait Codec: Sized {
+    /// Pushes encoded value to the stack.
+    fn encode(&self, state: &mut State);
+    /// Decodes value at `index` or returns `None` if it's not in the format.
+    fn decode(state: &mut State, index: Index) -> Option<Self>;
+}
+
+/// Restores top of the stack when dropped, even if a scope is left by panic.
+///
+/// The guard borrows the state, so it is used through the guard while it lives.
+pub struct StackGuard<'a> {
+    state: &'a mut State,
+    top: Index,
+    armed: bool,
+}
+
+impl<'a> StackGuard<'a> {
+    /// Makes guard which restores the current top of `state`.
+    pub fn new(state: &'a mut State) -> Self {
+        let top = state.get_top();
+        StackGuard {
+            state: state,
+            top: top,
+            armed: true,
+        }
+    }
+
+    /// Disarms the guard and leaves values on the stack.
+    pub fn release(mut self) {
+        self.armed = false;
+    }
+}
+
+impl<'a> Deref for StackGuard<'a> {
+    type Target = State;
+
+    fn deref(&self) -> &State {
+        self.state
+    }
+}
+
+impl<'a> DerefMut for StackGuard<'a> {
+    fn deref_mut(&mut self) -> &mut State {
+        self.state
+    }
+}
+
+impl<'a> Drop for StackGuard<'a> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.state.set_top(self.top);
+        }
+    }
+}
+
+/// Restores top of the stack by pointer, for `auto_cleanup` which lets the block borrow the state.
+#[doc(hidden)]
+pub struct RawStackGuard {
+    state: *mut lua_State,
+    top: Index,
+}
+
+impl RawStackGuard {
+    /// Makes guard which restores `top` of state `ls`.
+    ///
+    /// # Safety
+    ///
+    /// The state must outlive the guard.
+    pub unsafe fn from_ptr(ls: *mut lua_State, top: Index) -> Self {
+        RawStackGuard {
+            state: ls,
+            top: top,
+        }
+    }
+}
+
+impl Drop for RawStackGuard {
+    fn drop(&mut self) {
+        let mut state = unsafe { State::from_ptr(self.state) };
+        state.set_top(self.top);
+    }
+}
+
+/// Gives `&mut State` for `stack_guard` whether it's called with a state or a reference.
+#[doc(hidden)]
+pub trait AsStateMut {
+    fn as_state_mut(&mut self) -> &mut State;
+}
+
+impl AsStateMut for State {
+    fn as_state_mut(&mut self) -> &mut State {
+        self
+    }
+}
+
+struct Scope<F, R> {
+    body: Option<F>,
+    result: Option<R>,
+}
+
+unsafe extern "C" fn run_scope<F: FnOnce(&mut State) -> R, R>(ls: *mut lua_State) -> c_int {
+    let mut state = State::from_ptr(ls);
+    let scope = state.to_userdata(1) as *mut Scope<F, R>;
+    state.set_top(0);
+    if let Some(body) = (*scope).body.take() {
+        (*scope).result = Some(body(&mut state));
+    }
+    0
+}
+
+/// Runs `body` in protected mode, so errors of Lua are returned instead of jumping out.
+///
+/// The body runs in own frame of the stack like a called function, values it pushes
+/// are dropped and the stack is restored in any case.
+pub fn pcall_scope<R, F: FnOnce(&mut State) -> R>(state: &mut State, body: F) -> Result<R, String> {
+    let top = state.get_top();
+    let mut scope = Scope {
+        body: Some(body),
+        result: None,
+    };
+    state.push_fn(Some(run_scope::<F, R>));
+    state.push_light_userdata(&mut scope as *mut Scope<F, R>);
+    let result = if state.pcall(1, 0, 0).is_err() {
+        Err(state.to_type::<String>(-1).unwrap_or_else(|| "unknown error".to_owned()))
+    } else {
+        scope.result.take().ok_or_else(|| "body of scope wasn't run".to_owned())
+    };
+    state.set_top(top);
+    result
+}
+
+/// Cache of conversions of tables and userdata by identity of objects.
+///
+/// Conversions are kept in userdata of a table with weak keys, so the conversion is dropped
+/// when the object is collected, but changes of alive objects are not tracked.
+/// The table is kept in the registry until `release` is called.
+pub struct ConvertCache<T> {
+    table: Option<Reference>,
+    marker: PhantomData<T>,
+}
+
+#[doc(hidden)]
+pub fn convert_cache_meta<T>() -> String {
+    format!("lua_macros.convert_cache.{}", ::std::any::type_name::<T>())
+}
+
+impl<T> Default for ConvertCache<T> {
+    fn default() -> Self {
+        ConvertCache::new()
+    }
+}
+
+impl<T> ConvertCache<T> {
+    pub fn new() -> Self {
+        ConvertCache {
+            table: None,
+            marker: PhantomData,
+        }
+    }
+
+    /// Removes the table of conversions from the registry. Conversions are dropped by GC.
+    pub fn release(self, state: &mut State) {
+        if let Some(table) = self.table {
+            state.unreference(REGISTRYINDEX, table);
+        }
+    }
+}
+
+impl<T: Clone> ConvertCache<T> {
+    fn push_table(&mut self, state: &mut State) {
+        if let Some(ref table) = self.table {
+            state.raw_geti(REGISTRYINDEX, table.value() as Integer);
+            return;
+        }
+        state.new_table();
+        state.new_table();
+        state.push_string("k");
+        state.set_field(-2, "__mode");
+        state.set_metatable(-2);
+        state.push_value(-1);
+        self.table = Some(state.reference(REGISTRYINDEX));
+    }
+
+    /// Returns cached conversion of object at `index` or converts it with `convert`.
+    ///
+    /// Other values are converted every time.
+    pub fn convert<F>(&mut self, state: &mut State, index: Index, convert: F) -> Option<T>
+        where F: FnOnce(&mut State, Index) -> Option<T>
+    {
+        let index = state.abs_index(index);
+        if !state.is_table(index) && !state.is_userdata(index) {
+            return convert(state, index);
+        }
+        self.push_table(state);
+        state.push_value(index);
+        state.raw_get(-2);
+        // Metatable named by type is set by the cache only, so the userdata keeps `T`
+        let cached = unsafe { state.test_userdata_typed::<T>(-1, &convert_cache_meta::<T>()) }
+            .map(|value| value.clone());
+        state.pop(1);
+        if cached.is_some() {
+            state.pop(1);
+            return cached;
+        }
+        let value = convert(state, index);
+        if let Some(ref value) = value {
+            state.push_value(index);
+            unsafe {
+                let pointer = state.new_userdata_typed::<T>();
+                ::std::ptr::write(pointer, value.clone());
+            }
+            if state.new_metatable(&convert_cache_meta::<T>()) {
+                // Drops any `T` kept in userdata, not contexts only
+                state.push_fn(Some(drop_context::<T>));
+                state.set_field(-2, "__gc");
+            }
+            state.set_metatable(-2);
+            // Ephemeron entry: the conversion is collected together with the object
+            state.raw_set(-3);
+        }
+        state.pop(1);
+        value
+    }
+}
+
+/// Compares values at `a` and `b` by value, tables are compared by their contents.
+///
+/// Keys of tables are compared by identity. Tables which are compared again while being
+/// compared (cycles) are taken as equal.
+pub fn deep_equal(state: &mut State, a: Index, b: Index) -> bool {
+    let mut visited = Vec::new();
+    deep_equal_at(state, a, b, &mut visited)
+}
+
+fn deep_equal_at(state: &mut State, a: Index, b: Index, visited: &mut Vec<(*const c_void, *const c_void)>) -> bool {
+    let a = state.abs_index(a);
+    let b = state.abs_index(b);
+    if state.raw_equal(a, b) {
+        return true;
+    }
+    if !state.is_table(a) || !state.is_table(b) {
+        return false;
+    }
+    let pair = (state.to_pointer(a), state.to_pointer(b));
+    if visited.contains(&pair) {
+        return true;
+    }
+    visited.push(pair);
+    let top = state.get_top();
+    let mut count = 0;
+    let mut equal = true;
+    state.push_nil();
+    while state.next(a) {
+        count += 1;
+        state.push_value(-2);
+        state.raw_get(b);
+        if !deep_equal_at(state, -2, -1, visited) {
+            equal = false;
+            break;
+        }
+        state.pop(2); // Pop both values, `key` is left for `next`
+    }
+    state.set_top(top);
+    if equal {
+        state.push_nil();
+        while state.next(b) {
+            count -= 1;
+            state.pop(1);
+        }
+        equal = count == 0;
+    }
+    visited.pop();
+    equal
+}
+
+struct SliceView<T> {
+    ptr: *const T,
+    len: usize,
+}
+
+/// Pushes userdata which gives read-only access to items of the slice by 1-based indices.
+///
+/// Items are converted by `ToLua` on every access.
+///
+/// # Safety
+///
+/// The slice must outlive every use of the pushed value by scripts.
+pub unsafe fn push_slice_view<T: ToLua>(state: &mut State, slice: &[T]) {
+    unsafe extern "C" fn index<T: ToLua>(ls: *mut lua_State) -> c_int {
+        let mut state = State::from_ptr(ls);
+        let view = state.to_userdata(1) as *const SliceView<T>;
+        match state.to_type::<Integer>(2) {
+            Some(idx) if idx >= 1 && idx as usize <= (*view).len => {
+                (*(*view).ptr.offset(idx as isize - 1)).to_lua(&mut state);
+            },
+            _ => {
+                state.push_nil();
+            },
+        }
+        1
+    }
+
+    unsafe extern "C" fn len<T>(ls: *mut lua_State) -> c_int {
+        let mut state = State::from_ptr(ls);
+        let view = state.to_userdata(1) as *const SliceView<T>;
+        state.push_integer((*view).len as Integer);
+        1
+    }
+
+    unsafe extern "C" fn newindex(ls: *mut lua_State) -> c_int {
+        let mut state = State::from_ptr(ls);
+        state.push_string("slice view is read-only");
+        state.error()
+    }
+
+    let pointer = state.new_userdata_typed::<SliceView<T>>();
+    ::std::ptr::write(pointer, SliceView {
+        ptr: slice.as_ptr(),
+        len: slice.len(),
+    });
+    state.new_table();
+    state.push_fn(Some(index::<T>));
+    state.set_field(-2, "__index");
+    state.push_fn(Some(len::<T>));
+    state.set_field(-2, "__len");
+    state.push_fn(Some(newindex));
+    state.set_field(-2, "__newindex");
+    state.set_metatable(-2);
+}
+
+/// Token bucket which allows `rate` calls per second with bursts up to `rate` calls.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64) -> Self {
+        RateLimiter {
+            rate: rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes one token if available.
+    pub fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last);
+        self.last = now;
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Handlers to convert userdata to trait objects by name of their metatable.
+pub struct TypeRegistry<T: ?Sized> {
+    handlers: HashMap<String, Box<dyn Fn(&mut State, Index) -> Option<Box<T>>>>,
+}
+
+impl<T: ?Sized> TypeRegistry<T> {
+    pub fn new() -> Self {
+        TypeRegistry {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Registers handler for values with metatable `meta_name` (like `meta_name()` of `lua_userdata`).
+    pub fn register<F>(&mut self, meta_name: &str, handler: F)
+        where F: Fn(&mut State, Index) -> Option<Box<T>> + 'static
+    {
+        self.handlers.insert(meta_name.to_owned(), Box::new(handler));
+    }
+
+    /// Converts value at `index` with handler found by `__name` field of its metatable.
+    pub fn convert(&self, state: &mut State, index: Index) -> Option<Box<T>> {
+        let index = state.abs_index(index);
+        if !state.get_metatable(index) {
+            return None;
+        }
+        state.get_field(-1, "__name");
+        let name = state.to_type::<String>(-1);
+        state.pop(2);
+        name.and_then(|name| self.handlers.get(&name))
+            .and_then(|handler| handler(state, index))
+    }
+}
+
+/// Counts bytes allocated by states created with it.
+pub struct AllocTracker {
+    allocated: Cell<isize>,
+}
+
+unsafe extern "C" fn tracking_alloc(ud: *mut c_void, ptr: *mut c_void, osize: size_t, nsize: size_t) -> *mut c_void {
+    let tracker = &*(ud as *const AllocTracker);
+    // If `ptr` is null `osize` contains type of object instead of size
+    let old = if ptr.is_null() { 0 } else { osize as isize };
+    if nsize == 0 {
+        free(ptr);
+        tracker.allocated.set(tracker.allocated.get() - old);
+        ::std::ptr::null_mut()
+    } else {
+        let new = realloc(ptr, nsize);
+        if !new.is_null() {
+            tracker.allocated.set(tracker.allocated.get() + nsize as isize - old);
+        }
+        new
+    }
+}
+
+impl AllocTracker {
+    pub fn new() -> Self {
+        AllocTracker {
+            allocated: Cell::new(0),
+        }
+    }
+
+    /// Currently allocated bytes.
+    pub fn allocated(&self) -> isize {
+        self.allocated.get()
+    }
+
+    /// Creates state which allocates memory through this tracker.
+    ///
+    /// # Safety
+    ///
+    /// The tracker must outlive the state and the state must be closed with `lua_close`,
+    /// because it's not owned.
+    pub unsafe fn new_state(&self) -> State {
+        let ls = lua_newstate(Some(tracking_alloc), self as *const AllocTracker as *mut c_void);
+        State::from_ptr(ls)
+    }
+}
+
+/// Receiver of metrics which scripts emit through global `metrics` table.
+pub trait MetricsSink {
+    /// Adds `n` to counter `name`.
+    fn counter(&mut self, name: &str, n: Number);
+    /// Sets gauge `name` to `value`.
+    fn gauge(&mut self, name: &str, value: Number);
+}
+
+impl<T: MetricsSink> MetricsSink for Rc<RefCell<T>> {
+    fn counter(&mut self, name: &str, n: Number) {
+        self.borrow_mut().counter(name, n);
+    }
+
+    fn gauge(&mut self, name: &str, value: Number) {
+        self.borrow_mut().gauge(name, value);
+    }
+}
+
+const METRICS_SINK: &str = "lua_macros.metrics.sink";
+const METRICS_META: &str = "lua_macros.metrics";
+
+/// Keeps `sink` in the registry and sets global `metrics` table with `counter(name, n)`
+/// and `gauge(name, v)` functions. The sink registered before is dropped by GC.
+pub fn register_metrics(state: &mut State, sink: Box<dyn MetricsSink>) {
+    unsafe {
+        let pointer = state.new_userdata_typed::<Box<dyn MetricsSink>>();
+        ::std::ptr::write(pointer, sink);
+    }
+    if state.new_metatable(METRICS_META) {
+        state.push_fn(Some(metrics_gc));
+        state.set_field(-2, "__gc");
+    }
+    state.set_metatable(-2);
+    state.set_field(REGISTRYINDEX, METRICS_SINK);
+    state.new_table();
+    state.push_fn(Some(metrics_counter));
+    state.set_field(-2, "counter");
+    state.push_fn(Some(metrics_gauge));
+    state.set_field(-2, "gauge");
+    state.set_global("metrics");
+}
+
+unsafe fn metrics_emit(ls: *mut lua_State, gauge: bool) -> c_int {
+    let mut state = State::from_ptr(ls);
+    // Both arguments are checked before the name is copied, because errors don't run destructors
+    state.check_string(1);
+    let value = if gauge { state.check_number(2) } else { state.opt_number(2, 1.0) };
+    let name = state.to_str(1).unwrap_or_default().to_owned();
+    state.get_field(REGISTRYINDEX, METRICS_SINK);
+    if let Some(sink) = state.test_userdata_typed::<Box<dyn MetricsSink>>(-1, METRICS_META) {
+        if gauge {
+            sink.gauge(&name, value);
+        } else {
+            sink.counter(&name, value);
+        }
+    }
+    state.pop(1);
+    0
+}
+
+unsafe extern "C" fn metrics_counter(ls: *mut lua_State) -> c_int {
+    metrics_emit(ls, false)
+}
+
+unsafe extern "C" fn metrics_gauge(ls: *mut lua_State) -> c_int {
+    metrics_emit(ls, true)
+}
+
+unsafe extern "C" fn metrics_gc(ls: *mut lua_State) -> c_int {
+    let mut state = State::from_ptr(ls);
+    if let Some(sink) = state.test_userdata_typed::<Box<dyn MetricsSink>>(1, METRICS_META) {
+        ::std::ptr::drop_in_place(sink);
+    }
+    0
+}
+
+#[doc(hidden)]
+pub fn context_meta<T>() -> String {
+    format!("lua_macros.context.{}", ::std::any::type_name::<T>())
+}
+
+/// Keeps `value` in the registry under `key` for functions declared by `lua_ctx_fn`.
+///
+/// The value set before under the same `key` is dropped by GC.
+pub fn set_context<T>(state: &mut State, key: &str, value: T) {
+    unsafe {
+        let pointer = state.new_userdata_typed::<T>();
+        ::std::ptr::write(pointer, value);
+    }
+    if state.new_metatable(&context_meta::<T>()) {
+        state.push_fn(Some(drop_context::<T>));
+        state.set_field(-2, "__gc");
+    }
+    state.set_metatable(-2);
+    state.set_field(REGISTRYINDEX, key);
+}
+
+/// Calls `f` with the context kept under `key`. Returns `None` if there is no context of type `T`.
+pub fn with_context<T, R, F: FnOnce(&mut T) -> R>(state: &mut State, key: &str, f: F) -> Option<R> {
+    state.get_field(REGISTRYINDEX, key);
+    // Metatable named by type is set by `set_context` only, so the userdata keeps `T`
+    let result = unsafe { state.test_userdata_typed::<T>(-1, &context_meta::<T>()) }.map(f);
+    state.pop(1);
+    result
+}
+
+unsafe extern "C" fn drop_context<T>(ls: *mut lua_State) -> c_int {
+    let mut state = State::from_ptr(ls);
+    if let Some(value) = state.to_userdata_typed::<T>(1) {
+        ::std::ptr::drop_in_place(value);
+    }
+    0
+}
+
+/// Function of chunk which is loaded once and kept in the registry.
+pub struct CompiledChunk {
+    reference: Reference,
+}
+
+impl CompiledChunk {
+    /// Loads chunk without running it. Error contains the message of Lua.
+    pub fn compile(state: &mut State, source: &str) -> Result<Self, String> {
+        match state.load_string(source) {
+            ThreadStatus::Ok => Ok(CompiledChunk {
+                reference: state.reference(REGISTRYINDEX),
+            }),
+            _ => {
+                let msg = state.to_type::<String>(-1).unwrap_or_default();
+                state.pop(1);
+                Err(msg)
+            },
+        }
+    }
+
+    /// Calls the chunk with `args` in protected mode. Returns count of pushed results.
+    pub fn run(&self, state: &mut State, args: &[&dyn ToLua]) -> Result<Index, String> {
+        let top = state.get_top();
+        state.raw_geti(REGISTRYINDEX, self.reference.value() as Integer);
+        for arg in args {
+            arg.to_lua(state);
+        }
+        match state.pcall(args.len() as c_int, MULTRET, 0) {
+            ThreadStatus::Ok => Ok(state.get_top() - top),
+            _ => {
+                let msg = state.to_type::<String>(-1).unwrap_or_default();
+                state.set_top(top);
+                Err(msg)
+            },
+        }
+    }
+
+    /// Removes the chunk from the registry.
+    pub fn release(self, state: &mut State) {
+        state.unreference(REGISTRYINDEX, self.reference);
+    }
+}
+
+/// Box of userdata with flag `freeze` of `lua_userdata`.
+#[doc(hidden)]
+#[repr(C)]
+pub struct Frozen<T> {
+    pub value: T,
+    pub frozen: bool,
+}
+
+const PERMISSIONS: &str = "lua_macros.permissions";
+
+/// Grants permission which functions of `lua_guarded_fn` check.
+pub fn grant_permission(state: &mut State, permission: &str) {
+    state.get_field(REGISTRYINDEX, PERMISSIONS);
+    if !state.is_table(-1) {
+        state.pop(1);
+        state.new_table();
+        state.push_value(-1);
+        state.set_field(REGISTRYINDEX, PERMISSIONS);
+    }
+    state.push_bool(true);
+    state.set_field(-2, permission);
+    state.pop(1);
+}
+
+/// Revokes permission granted by `grant_permission`.
+pub fn revoke_permission(state: &mut State, permission: &str) {
+    state.get_field(REGISTRYINDEX, PERMISSIONS);
+    if state.is_table(-1) {
+        state.push_nil();
+        state.set_field(-2, permission);
+    }
+    state.pop(1);
+}
+
+/// Checks that permission is granted.
+pub fn has_permission(state: &mut State, permission: &str) -> bool {
+    state.get_field(REGISTRYINDEX, PERMISSIONS);
+    let granted = state.is_table(-1) && {
+        state.get_field(-1, permission);
+        let granted = state.to_bool(-1);
+        state.pop(1);
+        granted
+    };
+    state.pop(1);
+    granted
+}
+
+/// Type of slot of `convert_arguments`. Methods of `NumericSlot` and `AnySlot` tell
+/// if the slot reads Lua's numbers, which have to be checked before conversion.
+#[doc(hidden)]
+pub struct SlotType<T>(pub PhantomData<T>);
+
+/// Slots which accept values of type `number` only.
+#[doc(hidden)]
+pub trait NumericSlot {
+    fn is_numeric(&self) -> bool {
+        true
+    }
+}
+
+impl NumericSlot for SlotType<Integer> {}
+impl NumericSlot for SlotType<Number> {}
+
+/// Other slots, it's implemented for reference, so `NumericSlot` is taken first if it exists.
+#[doc(hidden)]
+pub trait AnySlot {
+    fn is_numeric(&self) -> bool {
+        false
+    }
+}
+
+impl<'a, T> AnySlot for &'a SlotType<T> {}
+
+/// Wrong argument reported by `convert_arguments!(@typed ...)`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArgError {
+    pub position: Index,
+    pub expected: String,
+    pub got: &'static str,
+}
+
+impl ArgError {
+    #[doc(hidden)]
+    pub fn new(state: &mut State, position: Index, names: &[&str]) -> Self {
+        let name = if position >= 1 && position as usize <= names.len() {
+            Some(names[position as usize - 1])
+        } else if position as usize > names.len() {
+            names.last().cloned().filter(|name| name.starts_with(".."))
+        } else {
+            None
+        };
+        // Variadic slot `..Type` expects `Type` in its first and all following positions
+        let expected = match name {
+            Some(name) => name.trim_start_matches("..").to_lowercase(),
+            None => "no value".to_owned(),
+        };
+        let got = match state.type_of(position) {
+            None | Some(Type::None) => "no value",
+            Some(Type::Nil) => "nil",
+            Some(Type::Boolean) => "boolean",
+            Some(Type::LightUserdata) | Some(Type::Userdata) => "userdata",
+            Some(Type::Number) => "number",
+            Some(Type::String) => "string",
+            Some(Type::Table) => "table",
+            Some(Type::Function) => "function",
+            Some(Type::Thread) => "thread",
+        };
+        ArgError {
+            position: position,
+            expected: expected,
+            got: got,
+        }
+    }
+
+    /// Reason without position which fits to `arg_error`.
+    pub fn reason(&self) -> String {
+        format!("{} expected, got {}", self.expected, self.got)
+    }
+}
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bad argument #{} ({})", self.position, self.reason())
+    }
+}
+
+impl Error for ArgError {}
+
+/// Raises argument error with message built at runtime.
+///
+/// The message is moved to the stack first, because the error doesn't run destructors.
+#[doc(hidden)]
+pub fn raise_arg_error(state: &mut State, arg: Index, message: String) -> c_int {
+    state.push_string(&message);
+    drop(message);
+    let ls = state.as_ptr();
+    unsafe {
+        let message = lua_tolstring(ls, -1, ::std::ptr::null_mut());
+        luaL_argerror(ls, arg, message)
+    }
+}
+
+/// Method which is missing in object checked by `require_interface`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MissingMethod(pub String);
+
+impl fmt::Display for MissingMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "missing method `{}`", self.0)
+    }
+}
+
+impl Error for MissingMethod {}
+
+/// Checks that object at `index` has functions in all fields of `methods`.
+///
+/// Fields are taken with metamethods, so methods of classes are taken into account.
+pub fn require_interface(state: &mut State, index: Index, methods: &[&str]) -> Result<(), MissingMethod> {
+    let index = state.abs_index(index);
+    if !state.is_table(index) && !state.is_userdata(index) {
+        return methods.first().map_or(Ok(()), |method| Err(MissingMethod(method.to_string())));
+    }
+    // Indexing could raise (userdata without `__index` or failing handler), so it's protected
+    state.push_value(index);
+    let object = state.reference(REGISTRYINDEX);
+    let mut current = 0;
+    let result = pcall_scope(state, |state| {
+        state.raw_geti(REGISTRYINDEX, object.value() as Integer);
+        for (position, method) in methods.iter().enumerate() {
+            current = position;
+            state.get_field(-1, method);
+            let found = state.is_fn(-1);
+            state.pop(1);
+            if !found {
+                return Some(position);
+            }
+        }
+        None
+    });
+    state.unreference(REGISTRYINDEX, object);
+    match result {
+        Ok(None) => Ok(()),
+        Ok(Some(position)) => Err(MissingMethod(methods[position].to_string())),
+        Err(_) => Err(MissingMethod(methods[current].to_string())),
+    }
+}
+
+fn generator_meta<T>() -> String {
+    format!("lua_macros.generator.{}", ::std::any::type_name::<T>())
+}
+
+/// Pushes userdata which owns iterator of `lua_generator_fn`.
+#[doc(hidden)]
+pub fn push_generator<T: ToLua + 'static>(state: &mut State, iter: Box<dyn Iterator<Item = T>>) {
+    unsafe {
+        let pointer = state.new_userdata_typed::<Box<dyn Iterator<Item = T>>>();
+        ::std::ptr::write(pointer, iter);
+    }
+    if state.new_metatable(&generator_meta::<T>()) {
+        state.push_fn(Some(drop_generator::<T>));
+        state.set_field(-2, "__gc");
+    }
+    state.set_metatable(-2);
+}
+
+/// Pushes the next item of iterator at `index`. Returns `false` if there are no more items.
+#[doc(hidden)]
+pub fn generator_next<T: ToLua + 'static>(state: &mut State, index: Index) -> bool {
+    // Metatable named by type is set by `push_generator` only, so the userdata keeps the iterator
+    let item = unsafe { state.test_userdata_typed::<Box<dyn Iterator<Item = T>>>(index, &generator_meta::<T>()) }
+        .and_then(|iter| iter.next());
+    match item {
+        Some(item) => {
+            state.push(item);
+            true
+        },
+        None => false,
+    }
+}
+
+unsafe extern "C" fn drop_generator<T: 'static>(ls: *mut lua_State) -> c_int {
+    let mut state = State::from_ptr(ls);
+    if let Some(iter) = state.to_userdata_typed::<Box<dyn Iterator<Item = T>>>(1) {
+        ::std::ptr::drop_in_place(iter);
+    }
+    0
+}
+
+/// Future which resumes coroutine on every poll until it returns.
+///
+/// The first result of coroutine is converted to `T`, errors are returned as messages.
+pub struct LuaFuture<T> {
+    thread: State,
+    reference: Reference,
+    started: bool,
+    result: PhantomData<T>,
+}
+
+impl<T: FromLua> LuaFuture<T> {
+    /// Makes future for `thread` which has a function with arguments on its stack.
+    ///
+    /// The thread object has to be on top of the stack of `state`, it's anchored in the registry.
+    pub fn new(state: &mut State, thread: State) -> Self {
+        let reference = state.reference(REGISTRYINDEX);
+        LuaFuture {
+            thread: thread,
+            reference: reference,
+            started: false,
+            result: PhantomData,
+        }
+    }
+}
+
+impl<T: FromLua> Future for LuaFuture<T> {
+    type Output = Result<T, String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let nargs = if this.started {
+            this.thread.set_top(0); // Drop yielded values
+            0
+        } else {
+            this.started = true;
+            this.thread.get_top() - 1 // Function itself is not an argument
+        };
+        match this.thread.resume(None, nargs) {
+            ThreadStatus::Yield => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            },
+            ThreadStatus::Ok => {
+                let result = this.thread.to_type::<T>(-1)
+                    .ok_or_else(|| "unexpected result of coroutine".to_owned());
+                Poll::Ready(result)
+            },
+            _ => {
+                let msg = this.thread.to_type::<String>(-1)
+                    .unwrap_or_else(|| "unknown error of coroutine".to_owned());
+                Poll::Ready(Err(msg))
+            },
+        }
+    }
+}
+
+impl<T> Drop for LuaFuture<T> {
+    fn drop(&mut self) {
+        // Threads share the registry
+        self.thread.unreference(REGISTRYINDEX, self.reference);
+    }
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T>>>;
+
+fn async_handle_meta<T>() -> String {
+    format!("lua_macros.async.{}", ::std::any::type_name::<T>())
+}
+
+fn noop_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(::std::ptr::null(), &VTABLE)
+    }
+    fn noop(_: *const ()) {
+    }
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    unsafe { Waker::from_raw(RawWaker::new(::std::ptr::null(), &VTABLE)) }
+}
+
+/// Pushes userdata handle with method `poll` which drives `future`.
+///
+/// `handle:poll()` returns `nil` while the future is pending and its output when it's ready,
+/// polling a finished handle is an error. Wakeups are ignored: the script polls the future
+/// itself, so it must not depend on a reactor of an async runtime.
+pub fn push_async_handle<T, F>(state: &mut State, future: F)
+    where T: ToLua + 'static, F: Future<Output = T> + 'static
+{
+    let future: BoxFuture<T> = Box::pin(future);
+    unsafe {
+        let pointer = state.new_userdata_typed::<Option<BoxFuture<T>>>();
+        ::std::ptr::write(pointer, Some(future));
+    }
+    if state.new_metatable(&async_handle_meta::<T>()) {
+        state.new_table();
+        state.push_fn(Some(poll_async_handle::<T>));
+        state.set_field(-2, "poll");
+        state.set_field(-2, "__index");
+        state.push_fn(Some(drop_async_handle::<T>));
+        state.set_field(-2, "__gc");
+    }
+    state.set_metatable(-2);
+}
+
+unsafe extern "C" fn poll_async_handle<T: ToLua + 'static>(ls: *mut lua_State) -> c_int {
+    let mut state = State::from_ptr(ls);
+    let meta = async_handle_meta::<T>();
+    let poll = state.test_userdata_typed::<Option<BoxFuture<T>>>(1, &meta).map(|slot| {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let poll = match *slot {
+            Some(ref mut future) => Some(future.as_mut().poll(&mut cx)),
+            None => None,
+        };
+        if let Some(Poll::Ready(_)) = poll {
+            *slot = None; // Future must not be polled after completion
+        }
+        poll
+    });
+    drop(meta);
+    match poll {
+        Some(Some(Poll::Ready(value))) => {
+            state.push(value);
+            1
+        },
+        Some(Some(Poll::Pending)) => {
+            state.push_nil();
+            1
+        },
+        Some(None) => {
+            state.push_string("operation is finished already");
+            state.error()
+        },
+        None => state.arg_error(1, "async handle expected"),
+    }
+}
+
+unsafe extern "C" fn drop_async_handle<T>(ls: *mut lua_State) -> c_int {
+    let mut state = State::from_ptr(ls);
+    if let Some(slot) = state.to_userdata_typed::<Option<BoxFuture<T>>>(1) {
+        ::std::ptr::drop_in_place(slot);
+    }
+    0
+}
+
+macro_rules! one_of_type {
+    ($name:ident { $( $var:ident ),+ }) => {
+        /// Value which was converted to the first suitable type.
+        #[derive(Clone, Debug, PartialEq)]
+        pub enum $name<$( $var ),+> {
+            $( $var($var), )+
+        }
+
+        impl<$( $var: FromLua ),+> FromLua for $name<$( $var ),+> {
+            fn from_lua(state: &mut State, index: Index) -> Option<Self> {
+                $(
+                if let Some(value) = state.to_type::<$var>(index) {
+                    return Some($name::$var(value));
+                }
+                )+
+                None
+            }
+        }
+    };
+}
+
+one_of_type!(OneOf2 { A, B });
+one_of_type!(OneOf3 { A, B, C });
+one_of_type!(OneOf4 { A, B, C, D });
+
+/// Clean up stack for the scope.
+#[macro_export]
+macro_rules! auto_cleanup {
+    ($state:ident, $b:block) => {{
+        // Guard keeps pointer only, so the block could borrow the state
+        let _guard = unsafe { $crate::RawStackGuard::from_ptr($state.as_ptr(), $state.get_top()) };
+        $b
+    }};
+}
+
+/// Convert arguments using `FromLua` trait.
+///
+/// Besides types, these slots are supported:
+/// * `_` - skips an argument.
+/// * `map<K, V>` - reads a table to `HashMap<K, V>` the same way as `lua_table_type` does.
+/// * `index1` - reads Lua's 1-based index (positive integer) to 0-based `usize`.
+/// * `one_of["a", "b"]` - reads a string which have to be one of listed and returns `&'static str`.
+/// * `format` - the last slot only, reads a format string and all remaining arguments and
+///   formats them with `string.format` to `String`. A failure of formatting (or the closed
+///   `string` library) is reported as the wrong format string.
+/// * `percent` - reads a number in range `0..=1` to `Percent`,
+///   `percent(100)` reads a number in range `0..=100` and scales it to fraction.
+/// * `finite Number` - reads a number which is not NaN or infinite.
+/// * `Option<Type>` - reads `nil` to `None` and other values to `Some(Type)`, the argument
+///   can't be omitted (use `?Type` for that).
+/// * `?Type` - reads an optional argument to `Option<Type>`, `nil` and omitted
+///   arguments are `None`. Only trailing slots could be optional, unlike `Option<Type>`.
+/// * `..Type` - the last slot only, reads all remaining arguments to `Vec<Type>`.
+///   Forms `format` and `..Type` aren't supported by `@strict false`, it's a compile error.
+///
+/// Form `convert_arguments!(@typed state, slots)` returns `ArgError` instead of position.
+///
+/// Form `convert_arguments!(state => Args { field: slot, .. })` returns declared struct `Args`
+/// with fields filled by arguments in order of fields.
+///
+/// Slots of types `Integer` and `Number` (including `?Integer`, `Option<Integer>`, `..Integer`,
+/// `finite Number`, `index1`, `percent` and types passed by other macros of the crate) accept
+/// values of type `number` only: `Integer` takes integers and floats with exact integer value,
+/// `Number` takes any number. Form `convert_arguments!(@coerce state, slots)` accepts numeric
+/// strings for these slots too, like `"5"` or `"0x10"`, the way arithmetic of Lua does.
+#[macro_export]
+macro_rules! convert_arguments {
+    (@strict $strict:tt, $state:ident, $($slots:tt)+) => {
+        convert_arguments!(@parse $strict, $state, [] $($slots)+)
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*]) => {
+        convert_arguments!(@collect $strict, $state, [$($done)*])
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*] , $($rest:tt)*) => {
+        convert_arguments!(@parse $strict, $state, [$($done)*] $($rest)*)
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*] _ $($rest:tt)*) => {
+        convert_arguments!(@parse $strict, $state, [$($done)* (skip)] $($rest)*)
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*] map < $key:ty , $val:ty > $($rest:tt)*) => {
+        convert_arguments!(@parse $strict, $state, [$($done)* (map $key, $val)] $($rest)*)
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*] index1 $($rest:tt)*) => {
+        convert_arguments!(@parse $strict, $state, [$($done)* (index1)] $($rest)*)
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*] one_of [ $( $opt:expr ),+ ] $($rest:tt)*) => {
+        convert_arguments!(@parse $strict, $state, [$($done)* (one_of $( $opt ),+)] $($rest)*)
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*] format) => {
+        convert_arguments!(@collect_rest $strict, $state, [$($done)*] (format))
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*] .. $from:ty) => {
+        convert_arguments!(@collect_rest $strict, $state, [$($done)*] (many $from))
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*] percent ( $scale:expr ) $($rest:tt)*) => {
+        convert_arguments!(@parse $strict, $state, [$($done)* (percent $scale)] $($rest)*)
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*] percent $($rest:tt)*) => {
+        convert_arguments!(@parse $strict, $state, [$($done)* (percent 1.0)] $($rest)*)
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*] Option < $from:ty > $($rest:tt)*) => {
+        convert_arguments!(@parse $strict, $state, [$($done)* (option $from)] $($rest)*)
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*] finite $from:ty) => {
+        convert_arguments!(@parse $strict, $state, [$($done)* (finite $from)])
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*] finite $from:ty , $($rest:tt)*) => {
+        convert_arguments!(@parse $strict, $state, [$($done)* (finite $from)] $($rest)*)
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*] ? $from:ty) => {
+        convert_arguments!(@parse_opt $strict, $state, [$($done)* (opt $from)])
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*] ? $from:ty , $($rest:tt)*) => {
+        convert_arguments!(@parse_opt $strict, $state, [$($done)* (opt $from)] $($rest)*)
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*] $from:ty) => {
+        convert_arguments!(@parse $strict, $state, [$($done)* (ty $from)])
+    };
+    (@parse $strict:tt, $state:ident, [$($done:tt)*] $from:ty , $($rest:tt)*) => {
+        convert_arguments!(@parse $strict, $state, [$($done)* (ty $from)] $($rest)*)
+    };
+    (@parse_opt $strict:tt, $state:ident, [$($done:tt)*]) => {
+        convert_arguments!(@collect $strict, $state, [$($done)*])
+    };
+    (@parse_opt $strict:tt, $state:ident, [$($done:tt)*] , $($rest:tt)*) => {
+        convert_arguments!(@parse_opt $strict, $state, [$($done)*] $($rest)*)
+    };
+    (@parse_opt $strict:tt, $state:ident, [$($done:tt)*] ? $from:ty) => {
+        convert_arguments!(@parse_opt $strict, $state, [$($done)* (opt $from)])
+    };
+    (@parse_opt $strict:tt, $state:ident, [$($done:tt)*] ? $from:ty , $($rest:tt)*) => {
+        convert_arguments!(@parse_opt $strict, $state, [$($done)* (opt $from)] $($rest)*)
+    };
+    (@parse_opt $strict:tt, $state:ident, [$($done:tt)*] $($rest:tt)+) => {
+        compile_error!("Required arguments can't follow optional ones.")
+    };
+    (@collect $strict:tt, $state:ident, [@coerce $($slot:tt)+]) => {
+        convert_arguments!(@collect_as $strict, true, $state, [$($slot)+])
+    };
+    (@collect $strict:tt, $state:ident, [$($slot:tt)+]) => {
+        convert_arguments!(@collect_as $strict, false, $state, [$($slot)+])
+    };
+    (@collect_as $strict:tt, $coerce:expr, $state:ident, [$($slot:tt)+]) => {{
+        use $crate::lua::Index;
+        #[allow(unused_variables)]
+        let coerce = $coerce;
+        let names = [$(stringify!($slot),)+];
+        let quantity = names.len() as Index;
+        let required = names.iter().filter(|name| !name.starts_with("(opt ")).count() as Index;
+        let top = $state.get_top();
+        auto_cleanup!($state, {
+            let mut collect = || {
+                let mut base = $state.get_top() - quantity;
+                if $strict && base < 0 && top >= required {
+                    base = 0; // Optional trailing arguments are omitted
+                }
+                if base < 0 {
+                    return Err(top + 1); // +1 because next arg expected
+                }
+                if $strict && base > 0 {
+                    return Err(top);
+                }
+                let mut position = 0;
+                let result = ($({
+                    position += 1;
+                    convert_arguments!(@unpack $slot, $state, base, position, coerce)
+                },)+);
+                Ok(result)
+            };
+            collect()
+        })
+    }};
+    (@collect_rest false, $state:ident, $($rest:tt)*) => {
+        compile_error!("Variadic slots are supported in strict mode only.")
+    };
+    (@collect_rest $strict:tt, $state:ident, [@coerce $($slot:tt)*] $rest:tt) => {
+        convert_arguments!(@collect_rest_as $strict, true, $state, [$($slot)*] $rest)
+    };
+    (@collect_rest $strict:tt, $state:ident, [$($slot:tt)*] $rest:tt) => {
+        convert_arguments!(@collect_rest_as $strict, false, $state, [$($slot)*] $rest)
+    };
+    (@collect_rest_as $strict:tt, $coerce:expr, $state:ident, [$($slot:tt)*] $rest:tt) => {{
+        use $crate::lua::Index;
+        #[allow(unused_variables)]
+        let coerce = $coerce;
+        let names: &[&str] = &[$(stringify!($slot),)*];
+        let quantity = names.len() as Index;
+        let top = $state.get_top();
+        auto_cleanup!($state, {
+            let mut collect = || {
+                // Tail takes all remaining values, so arguments have to start from the bottom
+                let base = 0;
+                if top < quantity {
+                    return Err(top + 1); // +1 because next arg expected
+                }
+                #[allow(unused_mut)]
+                let mut position = 0;
+                let result = ($({
+                    position += 1;
+                    convert_arguments!(@unpack $slot, $state, base, position, coerce)
+                },)* convert_arguments!(@unpack_rest $rest, $state, base, position, top, coerce),);
+                Ok(result)
+            };
+            collect()
+        })
+    }};
+    (@unpack_rest (format), $state:ident, $base:expr, $position:expr, $top:expr, $coerce:expr) => {{
+        let first = $base + $position + 1;
+        if $top < first || $state.type_of(first) != Some($crate::lua::Type::String) {
+            return Err(first);
+        }
+        $state.get_global("string");
+        if !$state.is_table(-1) {
+            return Err(first);
+        }
+        $state.get_field(-1, "format");
+        for idx in first..$top + 1 {
+            $state.push_value(idx);
+        }
+        if $state.pcall($top - first + 1, 1, 0).is_err() {
+            return Err(first);
+        }
+        match $state.to_type::<String>(-1) {
+            Some(v) => v,
+            None => {
+                return Err(first);
+            },
+        }
+    }};
+    (@unpack_rest (many $from:ty), $state:ident, $base:expr, $position:expr, $top:expr, $coerce:expr) => {{
+        let first = $base + $position + 1;
+        let mut items = ::std::vec::Vec::with_capacity(($top - first + 1).max(0) as usize);
+        for idx in first..$top + 1 {
+            let opt = if convert_arguments!(@numeric $from, $state, idx, $coerce) {
+                $state.to_type::<$from>(idx)
+            } else {
+                None
+            };
+            match opt {
+                Some(v) => items.push(v),
+                None => {
+                    return Err(idx);
+                },
+            }
+        }
+        items
+    }};
+    (@unpack (skip), $state:ident, $base:expr, $position:expr, $coerce:expr) => {()};
+    (@numeric $from:ty, $state:ident, $index:expr, $coerce:expr) => {{
+        // Strings are rejected before conversion, because Lua converts them to numbers
+        #[allow(unused_imports)]
+        use $crate::{AnySlot, NumericSlot};
+        let numeric = (&$crate::SlotType::<$from>(::std::marker::PhantomData)).is_numeric();
+        $coerce || !numeric || $state.type_of($index) == Some($crate::lua::Type::Number)
+    }};
+    (@unpack (ty $from:ty), $state:ident, $base:expr, $position:expr, $coerce:expr) => {{
+        let index = $base + $position;
+        let opt = if convert_arguments!(@numeric $from, $state, index, $coerce) {
+            $state.to_type::<$from>(index)
+        } else {
+            None
+        };
+        match opt {
+            Some(v) => v,
+            None => {
+                return Err($position);
+            },
+        }
+    }};
+    (@unpack (opt $from:ty), $state:ident, $base:expr, $position:expr, $coerce:expr) => {{
+        let index = $base + $position;
+        if index > $state.get_top() || $state.is_none_or_nil(index) {
+            None
+        } else {
+            let opt = if convert_arguments!(@numeric $from, $state, index, $coerce) {
+                $state.to_type::<$from>(index)
+            } else {
+                None
+            };
+            match opt {
+                Some(v) => Some(v),
+                None => {
+                    return Err($position);
+                },
+            }
+        }
+    }};
+    (@unpack (index1), $state:ident, $base:expr, $position:expr, $coerce:expr) => {{
+        let index = $base + $position;
+        let opt = if convert_arguments!(@numeric $crate::lua::Integer, $state, index, $coerce) {
+            $state.to_type::<$crate::lua::Integer>(index)
+        } else {
+            None
+        };
+        match opt {
+            Some(v) if v >= 1 => (v - 1) as usize,
+            _ => {
+                return Err($position);
+            },
+        }
+    }};
+    (@unpack (one_of $( $opt:expr ),+), $state:ident, $base:expr, $position:expr, $coerce:expr) => {{
+        let options: &[&'static str] = &[$( $opt ),+];
+        let found = $state.to_str($base + $position)
+            .and_then(|value| options.iter().find(|opt| **opt == value).cloned());
+        match found {
+            Some(v) => v,
+            None => {
+                return Err($position);
+            },
+        }
+    }};
+    (@unpack (percent $scale:expr), $state:ident, $base:expr, $position:expr, $coerce:expr) => {{
+        let index = $base + $position;
+        let opt = if convert_arguments!(@numeric $crate::lua::Number, $state, index, $coerce) {
+            $state.to_type::<$crate::lua::Number>(index)
+        } else {
+            None
+        };
+        let opt = opt.and_then(|value| $crate::Percent::from_scaled(value, $scale as $crate::lua::Number));
+        match opt {
+            Some(v) => v,
+            None => {
+                return Err($position);
+            },
+        }
+    }};
+    (@unpack (option $from:ty), $state:ident, $base:expr, $position:expr, $coerce:expr) => {{
+        let index = $base + $position;
+        if $state.is_nil(index) {
+            None
+        } else {
+            let opt = if convert_arguments!(@numeric $from, $state, index, $coerce) {
+                $state.to_type::<$from>(index)
+            } else {
+                None
+            };
+            match opt {
+                Some(v) => Some(v),
+                None => {
+                    return Err($position);
+                },
+            }
+        }
+    }};
+    (@unpack (finite $from:ty), $state:ident, $base:expr, $position:expr, $coerce:expr) => {{
+        let index = $base + $position;
+        let opt = if convert_arguments!(@numeric $from, $state, index, $coerce) {
+            $state.to_type::<$from>(index)
+        } else {
+            None
+        };
+        match opt {
+            Some(v) if v.is_finite() => v,
+            _ => {
+                return Err($position);
+            },
+        }
+    }};
+    (@unpack (map $key:ty, $val:ty), $state:ident, $base:expr, $position:expr, $coerce:expr) => {{
+        let opt = lua_table_type!(@read $state, $base + $position, $key, $val);
+        match opt {
+            Some(v) => v,
+            None => {
+                return Err($position);
+            },
+        }
+    }};
+    (@typed $state:ident, $($slots:tt)+) => {{
+        let result = convert_arguments!(@strict true, $state, $($slots)+);
+        result.map_err(|position| {
+            let names: &[&str] = &convert_arguments!(@names [] $($slots)+);
+            $crate::ArgError::new(&mut $state, position, names)
+        })
+    }};
+    (@names [$($name:expr),*]) => {
+        [$($name),*]
+    };
+    (@names [$($name:expr),*] , $($rest:tt)*) => {
+        convert_arguments!(@names [$($name),*] $($rest)*)
+    };
+    (@names [$($name:expr),*] _ $($rest:tt)*) => {
+        convert_arguments!(@names [$($name,)* "any value"] $($rest)*)
+    };
+    (@names [$($name:expr),*] map < $key:ty , $val:ty > $($rest:tt)*) => {
+        convert_arguments!(@names [$($name,)* "table"] $($rest)*)
+    };
+    (@names [$($name:expr),*] index1 $($rest:tt)*) => {
+        convert_arguments!(@names [$($name,)* "integer"] $($rest)*)
+    };
+    (@names [$($name:expr),*] one_of [ $( $opt:expr ),+ ] $($rest:tt)*) => {
+        convert_arguments!(@names [$($name,)* "string"] $($rest)*)
+    };
+    (@names [$($name:expr),*] format) => {
+        convert_arguments!(@names [$($name,)* "string"])
+    };
+    (@names [$($name:expr),*] percent ( $scale:expr ) $($rest:tt)*) => {
+        convert_arguments!(@names [$($name,)* "number"] $($rest)*)
+    };
+    (@names [$($name:expr),*] percent $($rest:tt)*) => {
+        convert_arguments!(@names [$($name,)* "number"] $($rest)*)
+    };
+    (@names [$($name:expr),*] finite $from:ty) => {
+        convert_arguments!(@names [$($name,)* "finite number"])
+    };
+    (@names [$($name:expr),*] finite $from:ty , $($rest:tt)*) => {
+        convert_arguments!(@names [$($name,)* "finite number"] $($rest)*)
+    };
+    (@names [$($name:expr),*] .. $from:ty) => {
+        convert_arguments!(@names [$($name,)* concat!("..", stringify!($from))])
+    };
+    (@names [$($name:expr),*] Option < $from:ty >) => {
+        convert_arguments!(@names [$($name,)* concat!(stringify!($from), " or nil")])
+    };
+    (@names [$($name:expr),*] Option < $from:ty > , $($rest:tt)*) => {
+        convert_arguments!(@names [$($name,)* concat!(stringify!($from), " or nil")] $($rest)*)
+    };
+    (@names [$($name:expr),*] ? $from:ty) => {
+        convert_arguments!(@names [$($name,)* stringify!($from)])
+    };
+    (@names [$($name:expr),*] ? $from:ty , $($rest:tt)*) => {
+        convert_arguments!(@names [$($name,)* stringify!($from)] $($rest)*)
+    };
+    (@names [$($name:expr),*] $from:ty) => {
+        convert_arguments!(@names [$($name,)* stringify!($from)])
+    };
+    (@names [$($name:expr),*] $from:ty , $($rest:tt)*) => {
+        convert_arguments!(@names [$($name,)* stringify!($from)] $($rest)*)
+    };
+    (@named $state:ident, $name:ident, [$($field:ident)+] [$($slots:tt)*]) => {
+        convert_arguments!(@strict true, $state, $($slots)*)
+            .map(|($($field,)+)| $name { $($field),+ })
+    };
+    (@named $state:ident, $name:ident, [$($field:ident)+] [$($slots:tt)*] , $next:ident : $($rest:tt)*) => {
+        convert_arguments!(@named $state, $name, [$($field)+ $next] [$($slots)* ,] $($rest)*)
+    };
+    (@named $state:ident, $name:ident, [$($field:ident)+] [$($slots:tt)*] $token:tt $($rest:tt)*) => {
+        convert_arguments!(@named $state, $name, [$($field)+] [$($slots)* $token] $($rest)*)
+    };
+    ($state:ident => $name:ident { $field:ident : $($rest:tt)+ }) => {
+        convert_arguments!(@named $state, $name, [$field] [] $($rest)+)
+    };
+    (@coerce $state:ident, $($slots:tt)+) =>
+        (convert_arguments!(@parse true, $state, [@coerce] $($slots)+));
+    ($state:ident, $($slots:tt)+) =>
+        (convert_arguments!(@strict true, $state, $($slots)+));
+}
+
+/// Makes wrapper to read table to hash map.
+///
+/// This macro add wrapper struct, because impossible to implement `FromLua` to `HashMap`
+/// because they are from other crates.
+///
+/// Optional third type parameter sets hasher of the map.
+#[macro_export]
+macro_rules! lua_table_type {
+    (@read $state:ident, $index:expr, $key:ty, $val:ty) => {
+        lua_table_type!(@read $state, $index, $key, $val, ::std::collections::hash_map::RandomState)
+    };
+    (@read $state:ident, $index:expr, $key:ty, $val:ty, $hasher:ty) => {
+        lua_table_type!(@read_map $state, $index, $key, $val, ::std::collections::HashMap<$key, $val, $hasher>)
+    };
+    (@read_map $state:ident, $index:expr, $key:ty, $val:ty, $map:ty) => {{
+        let index = $state.abs_index($index);
+        if !$state.is_table(index) {
+            None
+        } else {
+            let mut map: $map = Default::default();
+            let mut valid = true;
+            $state.push_nil();
+            while $state.next(index) {
+                // Non-strict, because this macro pushes to stack additional values
+                if let Ok((name, value)) = convert_arguments!(@strict false, $state, $key, $val) {
+                    map.insert(name, value);
+                    $state.pop(1); // Pop `key` only
+                } else {
+                    $state.pop(2); // Pop `key` and `value`, because `next` call returned `true`
+                    valid = false;
+                    break;
+                }
+            }
+            if valid { Some(map) } else { None }
+        }
+    }};
+    ($name:ident < $key:ty , $val:ty >) => {
+        lua_table_type!($name<$key, $val, ::std::collections::hash_map::RandomState>);
+    };
+    ($name:ident < $key:ty , $val:ty , $hasher:ty >) => {
+        pub struct $name(pub ::std::collections::HashMap<$key, $val, $hasher>);
+
+        impl $crate::lua::FromLua for $name {
+            fn from_lua(state: &mut $crate::lua::State, index: $crate::lua::Index) -> Option<Self> {
+                lua_table_type!(@read state, index, $key, $val, $hasher).map($name)
+            }
+        }
+
+        impl $crate::lua::ToLua for $name where $key: $crate::lua::ToLua, $val: $crate::lua::ToLua {
+            fn to_lua(&self, state: &mut $crate::lua::State) {
+                let $name(ref map) = *self;
+                state.create_table(0, map.len() as $crate::lua::libc::c_int);
+                for (key, value) in map {
+                    $crate::lua::ToLua::to_lua(key, state);
+                    $crate::lua::ToLua::to_lua(value, state);
+                    state.raw_set(-3);
+                }
+            }
+        }
+    };
+}
+
+/// Makes wrapper to read table to array.
+#[macro_export]
+macro_rules! lua_array_type {
+    (@read $state:ident, $index:expr, $val:ty) => {{
+        let index = $state.abs_index($index);
+        if !$state.is_table(index) {
+            None
+        } else {
+            let mut vec = ::std::vec::Vec::new();
+            let mut valid = true;
+            for idx in 1.. {
+                $state.geti(index, idx);
+                if $state.is_nil(-1) {
+                    $state.pop(1);
+                    break;
+                }
+                if let Ok((value,)) = convert_arguments!(@strict false, $state, $val) {
+                    vec.push(value);
+                    $state.pop(1);
+                } else {
+                    $state.pop(1);
+                    valid = false;
+                    break;
+                }
+            }
+            if valid { Some(vec) } else { None }
+        }
+    }};
+    ($name:ident < $val:ty >) => {
+        pub struct $name(pub ::std::vec::Vec<$val>);
+
+        impl $crate::lua::FromLua for $name {
+            fn from_lua(state: &mut $crate::lua::State, index: $crate::lua::Index) -> Option<Self> {
+                lua_array_type!(@read state, index, $val).map($name)
+            }
+        }
+
+        impl $crate::lua::ToLua for $name {
+            fn to_lua(&self, state: &mut $crate::lua::State) {
+                let $name(ref vec) = *self;
+                state.new_table();
+                let mut idx = 0;
+                for item in vec {
+                    idx += 1; // Starts from 1 too
+                    item.to_lua(state);
+                    state.raw_seti(-2, idx);
+                }
+            }
+        }
+    };
+}
+
+/// Makes wrapper to read table of arrays to hash map of vectors.
+///
+/// Every value is read the same way as `lua_array_type` does.
+#[macro_export]
+macro_rules! lua_multimap_type {
+    ($name:ident < $key:ty , $val:ty >) => {
+        pub struct $name(pub ::std::collections::HashMap<$key, ::std::vec::Vec<$val>>);
+
+        impl $crate::lua::FromLua for $name {
+            fn from_lua(state: &mut $crate::lua::State, index: $crate::lua::Index) -> Option<Self> {
+                if !state.is_table(index) {
+                    return None;
+                }
+                let index = state.abs_index(index);
+                let mut map = ::std::collections::HashMap::new();
+                state.push_nil();
+                while state.next(index) {
+                    let key = convert_arguments!(@strict false, state, $key, _).ok();
+                    let values = lua_array_type!(@read state, -1, $val);
+                    if let (Some((key, ())), Some(values)) = (key, values) {
+                        map.insert(key, values);
+                        state.pop(1); // Pop `value` only
+                    } else {
+                        state.pop(2); // Pop `key` and `value`, because `next` call returned `true`
+                        return None;
+                    }
+                }
+                Some($name(map))
+            }
+        }
+
+        impl $crate::lua::ToLua for $name where $key: $crate::lua::ToLua, $val: $crate::lua::ToLua {
+            fn to_lua(&self, state: &mut $crate::lua::State) {
+                let $name(ref map) = *self;
+                state.create_table(0, map.len() as $crate::lua::libc::c_int);
+                for (key, values) in map {
+                    $crate::lua::ToLua::to_lua(key, state);
+                    state.create_table(values.len() as $crate::lua::libc::c_int, 0);
+                    let mut idx = 0;
+                    for value in values {
+                        idx += 1; // Starts from 1 too
+                        $crate::lua::ToLua::to_lua(value, state);
+                        state.raw_seti(-2, idx);
+                    }
+                    state.raw_set(-3);
+                }
+            }
+        }
+    };
+}
+
+/// Makes wrapper to read array of exactly `N` items to `[T; N]`.
+///
+/// Tables with fewer items or with item at `N + 1` are rejected.
+#[macro_export]
+macro_rules! lua_fixed_array_type {
+    ($name:ident < $val:ty ; $len:expr >) => {
+        pub struct $name(pub [$val; $len]);
+
+        impl $crate::lua::FromLua for $name {
+            fn from_lua(state: &mut $crate::lua::State, index: $crate::lua::Index) -> Option<Self> {
+                if !state.is_table(index) {
+                    return None;
+                }
+                let index = state.abs_index(index);
+                state.geti(index, $len as $crate::lua::Integer + 1);
+                let longer = !state.is_nil(-1);
+                state.pop(1);
+                if longer {
+                    return None;
+                }
+                let mut items = ::std::vec::Vec::with_capacity($len);
+                for idx in 1..$len as $crate::lua::Integer + 1 {
+                    state.geti(index, idx);
+                    if let Ok((value,)) = convert_arguments!(@strict false, state, $val) {
+                        items.push(value);
+                        state.pop(1);
+                    } else {
+                        // Missing items are nils and fail too
+                        state.pop(1);
+                        return None;
+                    }
+                }
+                // Length is checked already, so conversion can't fail
+                let mut items = items.into_iter();
+                let array: [$val; $len] = ::std::array::from_fn(|_| items.next().unwrap());
+                Some($name(array))
+            }
+        }
+
+        impl $crate::lua::ToLua for $name {
+            fn to_lua(&self, state: &mut $crate::lua::State) {
+                let $name(ref array) = *self;
+                state.new_table();
+                let mut idx = 0;
+                for item in array.iter() {
+                    idx += 1; // Starts from 1 too
+                    item.to_lua(state);
+                    state.raw_seti(-2, idx);
+                }
+            }
+        }
+    };
+}
+
+/// Add userdata's methods to user's type.
+///
+/// Functions with names starting with `__` are set to metatable, others are
+/// methods and are put to `__index` table.
+///
+/// Name of metatable is `<Type>.Rust` unless it's set by `name = "..."` right after the type.
+///
+/// Mode `move` right after the type is for types which aren't `Clone`: instead of
+/// `ToLua` and `FromLua` it adds `push_owned` which moves value to userdata and
+/// `get_ref` which borrows value of userdata. Value is dropped by `__gc`.
+///
+/// Optional flags are listed after `;`:
+/// * `uservalue` - every instance gets own table to keep fields set by scripts.
+/// * `trace_access` - reports every read and write of fields to a sink set by
+///   `set_trace_sink` (enabled in debug builds only). Place it after other flags
+///   to trace access they add.
+/// * `freeze` - adds `freeze` method which makes instance read-only.
+/// * `tostring` - adds `__tostring` which formats value with `Debug`.
+/// * `eq` - adds `__eq` which compares values with `PartialEq`.
+#[macro_export]
+macro_rules! lua_userdata {
+    ($ud:ident, name = $meta:expr $(, $field:expr => $func:ident )* ; $( $flag:ident ),*) => {
+        lua_userdata!(@impl $ud, $meta, { }, { $( $field => $func ),* }, [ $( $flag )* ]);
+    };
+    ($ud:ident, name = $meta:expr $(, $field:expr => $func:ident )*) => {
+        lua_userdata!(@impl $ud, $meta, { }, { $( $field => $func ),* }, [ ]);
+    };
+    ($ud:ident, move $(, $field:expr => $func:ident )* ; $( $flag:ident ),*) => {
+        lua_userdata!(@impl $ud, concat!(stringify!($ud), ".Rust"), { }, { $( $field => $func ),* }, [ move $( $flag )* ]);
+    };
+    ($ud:ident, move $(, $field:expr => $func:ident )*) => {
+        lua_userdata!(@impl $ud, concat!(stringify!($ud), ".Rust"), { }, { $( $field => $func ),* }, [ move ]);
+    };
+    ($ud:ident $(, $field:expr => $func:ident )* ; $( $flag:ident ),*) => {
+        lua_userdata!(@impl $ud, concat!(stringify!($ud), ".Rust"), { }, { $( $field => $func ),* }, [ $( $flag )* ]);
+    };
+    ($ud:ident $(, $field:expr => $func:ident )*) => {
+        lua_userdata!(@impl $ud, concat!(stringify!($ud), ".Rust"), { }, { $( $field => $func ),* }, [ ]);
+    };
+    (@items move, $ud:ident) => {};
+    (@attach move, $ud:ident, $state:ident) => {{
+    }};
+    (@items shared, $ud:ident) => {};
+    (@attach shared, $ud:ident, $state:ident) => {{
+    }};
+    // Shared box drops the reference only, the value lives while Rust or other boxes refer to it
+    (@boxed $ud:ident, [ shared $( $flag:ident )* ]) => {
+        ::std::rc::Rc<::std::cell::RefCell<$ud>>
+    };
+    (@boxed $ud:ident, [ $( $flag:ident )* ]) => {
+        $ud
+    };
+    (@write $state:ident, $ud:ident, $value:expr, [ freeze $( $flag:ident )* ]) => {{
+        // Value goes first, so pointer to the box is pointer to the value too
+        let pointer = $state.new_userdata_typed::<$crate::Frozen<$ud>>();
+        ::std::ptr::write(pointer, $crate::Frozen { value: $value, frozen: false });
+    }};
+    (@write $state:ident, $ud:ident, $value:expr, [ $other:ident $( $flag:ident )* ]) => {
+        lua_userdata!(@write $state, $ud, $value, [ $( $flag )* ])
+    };
+    (@write $state:ident, $ud:ident, $value:expr, [ ]) => {{
+        let pointer = $state.new_userdata_typed::<$ud>();
+        ::std::ptr::write(pointer, $value);
+    }};
+    (@convert $ud:ident, [ move $( $flag:ident )* ]) => {
+        impl $ud {
+            /// Moves value to new userdata and pushes it to the stack.
+            pub fn push_owned(self, state: &mut $crate::lua::State) {
+                unsafe {
+                    lua_userdata!(@write state, $ud, self, [ $( $flag )* ]);
+                }
+                state.set_metatable_from_registry($ud::meta_name());
+                $(
+                lua_userdata!(@push $flag, $ud, state);
+                )*
+            }
+
+            /// Returns reference to value of userdata at `index`.
+            pub fn get_ref(state: &mut $crate::lua::State, index: $crate::lua::Index) -> Option<&mut $ud> {
+                unsafe {
+                    state.test_userdata_typed::<$ud>(index, $ud::meta_name())
+                }
+            }
+        }
+    };
+    (@convert $ud:ident, [ shared $( $flag:ident )* ]) => {
+        impl $ud {
+            /// Pushes userdata which refers to `cell`.
+            pub fn push_shared(state: &mut $crate::lua::State,
+                               cell: ::std::rc::Rc<::std::cell::RefCell<$ud>>) {
+                unsafe {
+                    let pointer = state.new_userdata_typed();
+                    ::std::ptr::write(pointer, cell);
+                }
+                state.set_metatable_from_registry($ud::meta_name());
+                $(
+                lua_userdata!(@push $flag, $ud, state);
+                )*
+            }
+
+            /// Returns reference to the cell of userdata at `index`.
+            pub fn get_shared(state: &mut $crate::lua::State, index: $crate::lua::Index)
+                -> Option<::std::rc::Rc<::std::cell::RefCell<$ud>>>
+            {
+                unsafe {
+                    state.test_userdata_typed::<::std::rc::Rc<::std::cell::RefCell<$ud>>>(index, $ud::meta_name())
+                        .map(|cell| cell.clone())
+                }
+            }
+        }
+    };
+    (@convert $ud:ident, [ $( $flag:ident )* ]) => {
+        impl $crate::lua::FromLua for $ud {
+            fn from_lua(state: &mut $crate::lua::State, index: $crate::lua::Index) -> Option<Self> {
+                unsafe {
+                    state.test_userdata_typed::<$ud>(index, $ud::meta_name())
+                        .map(|p| p.clone())
+                }
+            }
+        }
+
+        impl $crate::lua::ToLua for $ud {
+            fn to_lua(&self, state: &mut $crate::lua::State) {
+                unsafe {
+                    lua_userdata!(@write state, $ud, self.clone(), [ $( $flag )* ]);
+                }
+                state.set_metatable_from_registry($ud::meta_name());
+                $(
+                lua_userdata!(@push $flag, $ud, state);
+                )*
+            }
+        }
+    };
+    (@items uservalue, $ud:ident) => {
+        impl $ud {
+            /// Pushes uservalue table of instance at `index` to the stack.
+            pub fn uservalue(state: &mut $crate::lua::State, index: $crate::lua::Index) {
+                state.get_uservalue(index);
+            }
+
+            /// Pops table from the stack and sets it as uservalue of instance at `index`.
+            pub fn set_uservalue(state: &mut $crate::lua::State, index: $crate::lua::Index) {
+                let index = state.abs_index(index);
+                state.set_uservalue(index);
+            }
+        }
+    };
+    (@attach uservalue, $ud:ident, $state:ident) => {{
+        unsafe extern "C" fn index(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            state.get_uservalue(1);
+            state.push_value(2);
+            state.raw_get(-2);
+            if state.is_nil(-1) {
+                $crate::call_prev_index(&mut state, $crate::lua::ffi::lua_upvalueindex(1));
+            }
+            1
+        }
+        unsafe extern "C" fn newindex(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            // Fields are kept in uservalue unless previous handler takes them
+            if !$crate::call_prev_newindex(&mut state, $crate::lua::ffi::lua_upvalueindex(1)) {
+                state.get_uservalue(1);
+                state.push_value(2);
+                state.push_value(3);
+                state.raw_set(-3);
+            }
+            0
+        }
+        // Previous handlers (methods table or functions) become upvalues of wrappers
+        $state.get_field(-1, "__index");
+        $state.push_closure(Some(index), 1);
+        $state.set_field(-2, "__index");
+        $state.get_field(-1, "__newindex");
+        $state.push_closure(Some(newindex), 1);
+        $state.set_field(-2, "__newindex");
+    }};
+    (@push uservalue, $ud:ident, $state:ident) => {{
+        $state.new_table();
+        $state.set_uservalue(-2);
+    }};
+    (@items trace_access, $ud:ident) => {};
+    (@attach trace_access, $ud:ident, $state:ident) => {{
+        fn trace(state: &mut $crate::lua::State, action: &str) {
+            state.push_value(2); // Copy, because `to_str` converts numbers in place
+            let message = match state.to_str(-1) {
+                Some(key) => format!("{} {} {}", stringify!($ud), action, key),
+                None => format!("{} {} <non-string key>", stringify!($ud), action),
+            };
+            state.pop(1);
+            $crate::trace_access(&message);
+        }
+        unsafe extern "C" fn index(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            trace(&mut state, "index");
+            $crate::call_prev_index(&mut state, $crate::lua::ffi::lua_upvalueindex(1));
+            1
+        }
+        unsafe extern "C" fn newindex(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            trace(&mut state, "newindex");
+            if !$crate::call_prev_newindex(&mut state, $crate::lua::ffi::lua_upvalueindex(1)) {
+                state.push_string(concat!("can't set field of `", stringify!($ud), "`"));
+                state.error();
+            }
+            0
+        }
+        if cfg!(debug_assertions) {
+            // Previous handlers become upvalues of wrappers
+            $state.get_field(-1, "__index");
+            $state.push_closure(Some(index), 1);
+            $state.set_field(-2, "__index");
+            $state.get_field(-1, "__newindex");
+            $state.push_closure(Some(newindex), 1);
+            $state.set_field(-2, "__newindex");
+        }
+    }};
+    (@push trace_access, $ud:ident, $state:ident) => {{
+    }};
+    (@items freeze, $ud:ident) => {
+        impl $ud {
+            /// Checks that instance at `index` is frozen by `freeze` method.
+            pub fn is_frozen(state: &mut $crate::lua::State, index: $crate::lua::Index) -> bool {
+                // Instances with metatable of the type are boxed to `Frozen` by the flag
+                unsafe { state.test_userdata_typed::<$crate::Frozen<$ud>>(index, $ud::meta_name()) }
+                    .map_or(false, |boxed| boxed.frozen)
+            }
+        }
+    };
+    (@attach freeze, $ud:ident, $state:ident) => {{
+        unsafe extern "C" fn freeze(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            match state.test_userdata_typed::<$crate::Frozen<$ud>>(1, $ud::meta_name()) {
+                Some(boxed) => boxed.frozen = true,
+                None => return state.arg_error(1, concat!("`", stringify!($ud), "` expected")),
+            }
+            0
+        }
+        unsafe extern "C" fn index(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            if state.type_of(2) == Some($crate::lua::Type::String) && state.to_str(2) == Some("freeze") {
+                state.push_fn(Some(freeze));
+                return 1;
+            }
+            $crate::call_prev_index(&mut state, $crate::lua::ffi::lua_upvalueindex(1));
+            1
+        }
+        unsafe extern "C" fn newindex(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            if $ud::is_frozen(&mut state, 1) {
+                state.push_string(concat!("can't modify frozen `", stringify!($ud), "`"));
+                return state.error();
+            }
+            if !$crate::call_prev_newindex(&mut state, $crate::lua::ffi::lua_upvalueindex(1)) {
+                state.push_string(concat!("can't set field of `", stringify!($ud), "`"));
+                state.error();
+            }
+            0
+        }
+        // Previous handlers become upvalues of wrappers
+        $state.get_field(-1, "__index");
+        $state.push_closure(Some(index), 1);
+        $state.set_field(-2, "__index");
+        $state.get_field(-1, "__newindex");
+        $state.push_closure(Some(newindex), 1);
+        $state.set_field(-2, "__newindex");
+    }};
+    (@push freeze, $ud:ident, $state:ident) => {{
+    }};
+    (@items tostring, $ud:ident) => {};
+    (@attach tostring, $ud:ident, $state:ident) => {{
+        unsafe extern "C" fn tostring(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            let text = match state.test_userdata_typed::<$ud>(1, $ud::meta_name()) {
+                Some(ptr) => format!("{:?}", *ptr),
+                None => {
+                    state.arg_error(1, concat!("`", stringify!($ud), "` expected"));
+                    return 0;
+                },
+            };
+            state.push_string(&text);
+            1
+        }
+        $state.push_fn(Some(tostring));
+        $state.set_field(-2, "__tostring");
+    }};
+    (@push tostring, $ud:ident, $state:ident) => {{
+    }};
+    (@items eq, $ud:ident) => {};
+    (@attach eq, $ud:ident, $state:ident) => {{
+        unsafe extern "C" fn eq(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            // Lua calls `__eq` of other type too if only one operand is of this type
+            let left = state.test_userdata_typed::<$ud>(1, $ud::meta_name());
+            let right = state.test_userdata_typed::<$ud>(2, $ud::meta_name());
+            let equal = match (left, right) {
+                (Some(left), Some(right)) => *left == *right,
+                _ => false,
+            };
+            state.push_bool(equal);
+            1
+        }
+        $state.push_fn(Some(eq));
+        $state.set_field(-2, "__eq");
+    }};
+    (@push eq, $ud:ident, $state:ident) => {{
+    }};
+    (@impl $ud:ident, $meta:expr, { $( $method:ident => $mfunc:ident ),* }, { $( $field:expr => $func:expr ),* }, [ $( $flag:ident )* ]) => {
+        $(
+        lua_userdata!(@items $flag, $ud);
+        )*
+
+        impl $ud {
+            pub fn meta_name() -> &'static str {
+                $meta
+            }
+
+            pub fn attach(state: &mut $crate::lua::State) {
+                let created = state.new_metatable($ud::meta_name());
+                let methods: &[(&str, $crate::lua::Function)] = &[$( (stringify!($method), Some($mfunc)) ),*];
+                let fields: &[(&str, $crate::lua::Function)] = &[$( ($field, Some($func)) ),*];
+                // Metamethods stay in metatable, other functions are methods
+                let is_meta = |name: &str| name.starts_with("__");
+                let mut index = methods.iter()
+                    .chain(fields.iter().filter(|&&(name, _)| !is_meta(name)))
+                    .peekable();
+                if index.peek().is_some() {
+                    state.new_table();
+                    for &(name, func) in index {
+                        state.push_fn(func);
+                        state.set_field(-2, name);
+                    }
+                    state.set_field(-2, "__index");
+                }
+                for &(name, func) in fields.iter().filter(|&&(name, _)| is_meta(name)) {
+                    state.push_fn(func);
+                    state.set_field(-2, name);
+                }
+                $(
+                lua_userdata!(@attach $flag, $ud, state);
+                )*
+                state.push_fn(Some($ud::drop_it));
+                state.set_field(-2, "__gc");
+                state.pop(1); // pop metatable
+                if !created {
+                    panic!("Metatable '{}' already exists.", $ud::meta_name());
+                }
+            }
+
+            // Metatable is removed after drop, so the value is never dropped (or read) twice
+            // even if the finalizer resurrects it
+            unsafe extern "C" fn drop_it(state: *mut $crate::lua::ffi::lua_State) -> i32 {
+                let mut state = $crate::lua::State::from_ptr(state);
+                let dropped = match state.test_userdata_typed::<lua_userdata!(@boxed $ud, [ $( $flag )* ])>(1, $ud::meta_name()) {
+                    Some(ptr) => {
+                        ::std::ptr::drop_in_place(ptr);
+                        true
+                    },
+                    None => false,
+                };
+                if dropped {
+                    state.push_nil();
+                    state.set_metatable(1);
+                }
+                0
+            }
+        }
+
+        lua_userdata!(@convert $ud, [ $( $flag )* ]);
+    };
+}
+
+/// Add userdata which is shared by reference between Lua and Rust.
+///
+/// Box of userdata keeps `Rc<RefCell<T>>`, so all references see the same value and
+/// changes made by methods are visible everywhere.
+#[macro_export]
+macro_rules! lua_shared_userdata {
+    ($ud:ident $(, $field:expr => $func:ident )*) => {
+        lua_userdata!(@impl $ud, concat!(stringify!($ud), ".Shared"), { }, { $( $field => $func ),* }, [ shared ]);
+    };
+}
+
+/// Implements conversion of type from Lua's string using `FromStr` and `Display` traits.
+#[macro_export]
+macro_rules! lua_str_enum {
+    ($name:ty) => {
+        impl $crate::lua::FromLua for $name {
+            fn from_lua(state: &mut $crate::lua::State, index: $crate::lua::Index) -> Option<Self> {
+                if state.type_of(index) != Some($crate::lua::Type::String) {
+                    return None;
+                }
+                state.to_str(index).and_then(|s| s.parse().ok())
+            }
+        }
+
+        impl $crate::lua::ToLua for $name {
+            fn to_lua(&self, state: &mut $crate::lua::State) {
+                state.push_string(&self.to_string());
+            }
+        }
+    };
+}
+
+/// Declares userdata type with methods table and metamethods at once.
+///
+/// Methods are placed to the table which is set as `__index` of metatable.
+#[macro_export]
+macro_rules! lua_type {
+    ($ud:ident {
+        methods: { $( $method:ident => $mfunc:ident ),* $(,)? },
+        meta: { $( $field:expr => $func:ident ),* $(,)? } $(,)?
+    }) => {
+        lua_userdata!(@impl $ud, concat!(stringify!($ud), ".Rust"), { $( $method => $mfunc ),* }, { $( $field => $func ),* }, [ ]);
+    };
+}
+
+/// Maps variants of enum to Lua's values and adds constants table for it.
+///
+/// Values are compared with `raw_equal`, so strings could be used as tags.
+///
+/// Optional flags:
+/// * `call` - table becomes callable and validates value to a variant.
+/// * `all` - table gets field `all` with array of values in order of declaration.
+#[macro_export]
+macro_rules! lua_enum {
+    (@all $name:ident, $state:ident) => {{
+        $name::push_all($state);
+        $state.set_field(-2, "all");
+    }};
+    (@call $name:ident, $state:ident) => {{
+        unsafe extern "C" fn call(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            // First argument is the table itself
+            match state.to_type::<$name>(2) {
+                Some(value) => {
+                    state.push(value);
+                    1
+                },
+                None => {
+                    state.push_string(concat!("invalid value for `", stringify!($name), "`"));
+                    state.error()
+                },
+            }
+        }
+        $state.new_table();
+        $state.push_fn(Some(call));
+        $state.set_field(-2, "__call");
+        $state.set_metatable(-2);
+    }};
+    ($name:ident { $( $variant:ident => $value:expr ),+ $(,)? } $(, $flag:ident)*) => {
+        impl $name {
+            pub fn attach(state: &mut $crate::lua::State) {
+                state.new_table();
+                $(
+                state.push($value);
+                state.set_field(-2, stringify!($variant));
+                )+
+                $(
+                lua_enum!(@ $flag $name, state);
+                )*
+                state.set_global(stringify!($name));
+            }
+
+            /// Pushes array of values of all variants in order of declaration.
+            pub fn push_all(state: &mut $crate::lua::State) {
+                state.new_table();
+                let mut idx = 0;
+                $(
+                idx += 1; // Starts from 1 too
+                state.push($value);
+                state.raw_seti(-2, idx);
+                )+
+            }
+        }
+
+        impl $crate::lua::FromLua for $name {
+            fn from_lua(state: &mut $crate::lua::State, index: $crate::lua::Index) -> Option<Self> {
+                let index = state.abs_index(index);
+                $(
+                state.push($value);
+                let matched = state.raw_equal(index, -1);
+                state.pop(1);
+                if matched {
+                    return Some($name::$variant);
+                }
+                )+
+                None
+            }
+        }
+
+        impl $crate::lua::ToLua for $name {
+            fn to_lua(&self, state: &mut $crate::lua::State) {
+                match *self {
+                    $( $name::$variant => state.push($value), )+
+                }
+            }
+        }
+    };
+}
+
+/// Pops `n` values from stack or returns error if stack has less values.
+#[macro_export]
+macro_rules! safe_pop {
+    ($state:ident, $n:expr) => {{
+        let n: $crate::lua::Index = $n;
+        let top = $state.get_top();
+        if n < 0 || n > top {
+            Err(format!("can't pop {} values from stack of {}", n, top))
+        } else {
+            $state.pop(n);
+            Ok(())
+        }
+    }};
+}
+
+/// Registers loader of module in `package.preload` table.
+///
+/// The `package` library has to be opened before.
+#[macro_export]
+macro_rules! register_loader {
+    ($state:ident, $name:expr, $loader:expr) => {{
+        auto_cleanup!($state, {
+            $state.get_global("package");
+            if !$state.is_table(-1) {
+                panic!("Library `package` is not opened.");
+            }
+            $state.get_field(-1, "preload");
+            $state.push_fn(Some($loader));
+            $state.set_field(-2, $name);
+        })
+    }};
+}
+
+/// Creates `TableBuilder` which pushes table to the stack.
+#[macro_export]
+macro_rules! table_builder {
+    ($state:ident) => {
+        $crate::TableBuilder::new(&mut $state)
+    };
+}
+
+/// Pushes table built of pairs `{ key => value }` or items `[ value ]`.
+///
+/// Values are pushed by `ToLua`. Returns `Pushed`, so nested `push_table` calls
+/// could be used as values.
+#[macro_export]
+macro_rules! push_table {
+    ($state:ident, { $( $key:expr => $value:expr ),* $(,)? }) => {{
+        let keys: &[&str] = &[$( stringify!($key) ),*];
+        $state.create_table(0, keys.len() as $crate::lua::libc::c_int);
+        $(
+        $state.push($key);
+        let value = $value;
+        $state.push(value);
+        $state.raw_set(-3);
+        )*
+        $crate::Pushed
+    }};
+    ($state:ident, [ $( $value:expr ),* $(,)? ]) => {{
+        let items: &[&str] = &[$( stringify!($value) ),*];
+        $state.create_table(items.len() as $crate::lua::libc::c_int, 0);
+        #[allow(unused_mut)]
+        let mut idx = 0;
+        $(
+        let value = $value;
+        $state.push(value);
+        idx += 1; // Starts from 1 too
+        $state.raw_seti(-2, idx);
+        )*
+        $crate::Pushed
+    }};
+}
+
+/// Reads value trying types in order and wraps the first success to `OneOf2`,
+/// `OneOf3` or `OneOf4` enum.
+#[macro_export]
+macro_rules! to_any {
+    ($state:ident, $index:expr, [$a:ty, $b:ty]) => {
+        $state.to_type::<$crate::OneOf2<$a, $b>>($index)
+    };
+    ($state:ident, $index:expr, [$a:ty, $b:ty, $c:ty]) => {
+        $state.to_type::<$crate::OneOf3<$a, $b, $c>>($index)
+    };
+    ($state:ident, $index:expr, [$a:ty, $b:ty, $c:ty, $d:ty]) => {
+        $state.to_type::<$crate::OneOf4<$a, $b, $c, $d>>($index)
+    };
+}
+
+/// Copies items of iterator to a new array, pushes it and returns `1` as count of results.
+#[macro_export]
+macro_rules! return_iter_snapshot {
+    ($state:ident, $iter:expr) => {{
+        push_iter_array!($state, $iter);
+        1
+    }};
+}
+
+/// Defines global which value is computed with closure on the first access.
+#[macro_export]
+macro_rules! lazy_global {
+    ($state:ident, $name:expr, $init:expr) => {{
+        type Init = Option<Box<dyn FnOnce(&mut $crate::lua::State)>>;
+
+        unsafe extern "C" fn index(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            state.get_field($crate::lua::REGISTRYINDEX, "lua_macros.lazy_globals");
+            if !state.is_table(-1) {
+                $crate::call_prev_index(&mut state, $crate::lua::ffi::lua_upvalueindex(1));
+                return 1;
+            }
+            state.push_value(2);
+            state.raw_get(-2);
+            let init = state.to_userdata_typed::<Init>(-1).and_then(|init| init.take());
+            match init {
+                Some(init) => {
+                    // Remove used initializer: `lazy[key] = nil`
+                    state.push_value(2);
+                    state.push_nil();
+                    state.raw_set(-4);
+                    init(&mut state);
+                    // Cache the value: `globals[key] = value`
+                    state.push_value(2);
+                    state.push_value(-2);
+                    state.raw_set(1);
+                },
+                None => {
+                    $crate::call_prev_index(&mut state, $crate::lua::ffi::lua_upvalueindex(1));
+                },
+            }
+            1
+        }
+
+        unsafe extern "C" fn drop_it(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            if let Some(ptr) = state.to_userdata_typed::<Init>(1) {
+                ::std::ptr::drop_in_place(ptr);
+            }
+            0
+        }
+
+        let init = $init;
+        auto_cleanup!($state, {
+            $state.get_field($crate::lua::REGISTRYINDEX, "lua_macros.lazy_globals");
+            let installed = $state.is_table(-1);
+            if !installed {
+                $state.pop(1);
+                $state.new_table();
+                $state.push_value(-1);
+                $state.set_field($crate::lua::REGISTRYINDEX, "lua_macros.lazy_globals");
+            }
+            unsafe {
+                let pointer = $state.new_userdata_typed::<Init>();
+                let boxed: Init = Some(Box::new(move |state: &mut $crate::lua::State| {
+                    state.push(init());
+                }));
+                ::std::ptr::write(pointer, boxed);
+            }
+            if $state.new_metatable("lua_macros.LazyGlobal") {
+                $state.push_fn(Some(drop_it));
+                $state.set_field(-2, "__gc");
+            }
+            $state.set_metatable(-2);
+            $state.set_field(-2, $name);
+
+            // Handler is set once, with previous `__index` as its upvalue
+            if !installed {
+                $state.raw_geti($crate::lua::REGISTRYINDEX, $crate::lua::ffi::LUA_RIDX_GLOBALS as $crate::lua::Integer);
+                if !$state.get_metatable(-1) {
+                    $state.new_table();
+                    $state.push_value(-1);
+                    $state.set_metatable(-3);
+                }
+                $state.get_field(-1, "__index");
+                $state.push_closure(Some(index), 1);
+                $state.set_field(-2, "__index");
+            }
+        })
+    }};
+}
+
+/// Runs chunk like `do_string`, but with `debug.traceback` as message handler.
+///
+/// The `debug` library has to be opened before.
+#[macro_export]
+macro_rules! do_string_traced {
+    ($state:ident, $chunk:expr) => {{
+        let msgh = $state.get_top() + 1;
+        $state.get_global("debug");
+        $state.get_field(-1, "traceback");
+        $state.remove(msgh); // Remove `debug` table, handler takes its place
+        let status = $state.load_string($chunk);
+        let status = if status.is_err() {
+            status
+        } else {
+            $state.pcall(0, $crate::lua::MULTRET, msgh)
+        };
+        $state.remove(msgh);
+        status
+    }};
+}
+
+/// Runs block which returns `ThreadStatus` and prepends `context` to error message
+/// left on top of the stack if it fails.
+#[macro_export]
+macro_rules! with_error_context {
+    ($state:ident, $context:expr, $b:block) => {{
+        let status: $crate::lua::ThreadStatus = $b;
+        if status.is_err() {
+            let msg = match $state.to_str(-1) {
+                Some(msg) => format!("{}: {}", $context, msg),
+                None => format!("{}: error object is not a string", $context),
+            };
+            $state.pop(1);
+            $state.push_string(&msg);
+        }
+        status
+    }};
+}
+
+/// Converts value to JSON text. Requires `json` feature.
+#[cfg(feature = "json")]
+#[macro_export]
+macro_rules! lua_to_json {
+    ($state:ident, $index:expr) => {
+        $crate::json::to_json_value(&mut $state, $index).map(|value| value.to_string())
+    };
+}
+
+/// Parses JSON text and pushes its value. Requires `json` feature.
+#[cfg(feature = "json")]
+#[macro_export]
+macro_rules! json_to_lua {
+    ($state:ident, $json:expr) => {
+        $crate::json::push_json(&mut $state, $json)
+    };
+}
+
+/// Registers global function which describes place of its call.
+#[macro_export]
+macro_rules! register_debug_where {
+    ($state:ident, $name:expr) => {{
+        unsafe extern "C" fn debug_where(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            use std::ffi::CStr;
+            let mut ar: $crate::lua::ffi::lua_Debug = ::std::mem::zeroed();
+            // Level 1 is a function which called this one
+            let description = if $crate::lua::ffi::lua_getstack(ls, 1, &mut ar) != 0 {
+                $crate::lua::ffi::lua_getinfo(ls, b"Sln\0".as_ptr() as *const _, &mut ar);
+                let source = CStr::from_ptr(ar.short_src.as_ptr()).to_string_lossy();
+                let name = if ar.name.is_null() {
+                    "?".into()
+                } else {
+                    CStr::from_ptr(ar.name).to_string_lossy()
+                };
+                format!("{}:{}: in function '{}'", source, ar.currentline, name)
+            } else {
+                "?".to_owned()
+            };
+            let mut state = $crate::lua::State::from_ptr(ls);
+            state.push_string(&description);
+            1
+        }
+        $state.push_fn(Some(debug_where));
+        $state.set_global($name);
+    }};
+}
+
+/// Compares values by contents, see `deep_equal`.
+#[macro_export]
+macro_rules! lua_table_eq {
+    ($state:ident, $a:expr, $b:expr) => {
+        $crate::deep_equal(&mut $state, $a, $b)
+    };
+}
+
+/// Makes userdata type which gives scripts access to shared `HashMap` with string keys.
+///
+/// Reads and writes of fields by scripts go directly to the map, `pairs` iterates over it
+/// in order of keys. Assigning `nil` removes the key.
+#[macro_export]
+macro_rules! lua_map_proxy {
+    ($name:ident < $val:ty >) => {
+        #[derive(Clone)]
+        pub struct $name(pub ::std::rc::Rc<::std::cell::RefCell<::std::collections::HashMap<String, $val>>>);
+
+        impl $name {
+            pub fn new() -> Self {
+                $name(Default::default())
+            }
+
+            unsafe extern "C" fn map_index(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+                use $crate::lua::ToLua;
+                let mut state = $crate::lua::State::from_ptr(ls);
+                let proxy = state.to_type::<$name>(1);
+                let key = state.to_type::<String>(2);
+                if let (Some(proxy), Some(key)) = (proxy, key) {
+                    if let Some(value) = proxy.0.borrow().get(&key) {
+                        value.to_lua(&mut state);
+                        return 1;
+                    }
+                }
+                state.push_nil();
+                1
+            }
+
+            unsafe extern "C" fn map_newindex(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+                let mut state = $crate::lua::State::from_ptr(ls);
+                let proxy = state.to_type::<$name>(1);
+                let key = if state.type_of(2) == Some($crate::lua::Type::String) {
+                    state.to_type::<String>(2)
+                } else {
+                    None
+                };
+                // Values are dropped before raising, because `arg_error` doesn't return
+                let error = match (proxy, key) {
+                    (Some(proxy), Some(key)) => {
+                        if state.is_nil(3) {
+                            proxy.0.borrow_mut().remove(&key);
+                            None
+                        } else {
+                            match state.to_type::<$val>(3) {
+                                Some(value) => {
+                                    proxy.0.borrow_mut().insert(key, value);
+                                    None
+                                },
+                                None => Some((3, "unsupported value")),
+                            }
+                        }
+                    },
+                    (None, _) => Some((1, concat!("`", stringify!($name), "` expected"))),
+                    (Some(_), None) => Some((2, "string key expected")),
+                };
+                if let Some((pos, msg)) = error {
+                    state.arg_error(pos, msg);
+                }
+                0
+            }
+
+            unsafe extern "C" fn map_next(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+                use $crate::lua::ToLua;
+                let mut state = $crate::lua::State::from_ptr(ls);
+                let proxy = match state.to_type::<$name>(1) {
+                    Some(proxy) => proxy,
+                    None => {
+                        return 0;
+                    },
+                };
+                let map = proxy.0.borrow();
+                let mut keys: Vec<&String> = map.keys().collect();
+                keys.sort();
+                let position = if state.is_nil(2) {
+                    Some(0)
+                } else {
+                    state.to_type::<String>(2)
+                        .and_then(|prev| keys.iter().position(|key| **key == prev))
+                        .map(|pos| pos + 1)
+                };
+                match position.and_then(|pos| keys.get(pos)) {
+                    Some(key) => {
+                        state.push_string(key);
+                        map[*key].to_lua(&mut state);
+                        2
+                    },
+                    None => {
+                        state.push_nil();
+                        1
+                    },
+                }
+            }
+
+            unsafe extern "C" fn map_pairs(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+                let mut state = $crate::lua::State::from_ptr(ls);
+                state.push_fn(Some($name::map_next));
+                state.push_value(1);
+                state.push_nil();
+                3
+            }
+        }
+
+        lua_userdata!(@impl $name, concat!(stringify!($name), ".Rust"), { }, {
+            "__index" => $name::map_index,
+            "__newindex" => $name::map_newindex,
+            "__pairs" => $name::map_pairs
+        }, [ ]);
+    };
+}
+
+/// Pushes read-only view of the slice, see `push_slice_view`.
+///
+/// It's unsafe, because the slice must outlive every use of the view by scripts.
+#[macro_export]
+macro_rules! push_struct_slice_view {
+    ($state:ident, $slice:expr) => {
+        $crate::push_slice_view(&mut $state, &$slice[..])
+    };
+}
+
+/// Declares Lua function which takes fields of a single table argument.
+///
+/// Fields with default values are optional. Without return type the function returns nothing.
+#[macro_export]
+macro_rules! lua_kwargs_fn {
+    (@field $state:ident, $field:ident, $ty:ty $(, $default:expr)?) => {{
+        let key = stringify!($field).trim_end_matches('_');
+        #[allow(unreachable_code)]
+        let default = || -> Option<$ty> {
+            $( return Some($default); )?
+            None
+        };
+        $state.get_field(1, key);
+        let value = if $state.is_nil(-1) {
+            match default() {
+                Some(value) => value,
+                None => return Err(format!("missing field '{}'", key)),
+            }
+        } else {
+            match $state.to_type::<$ty>(-1) {
+                Some(value) => value,
+                None => return Err(format!("wrong type of field '{}'", key)),
+            }
+        };
+        $state.pop(1);
+        value
+    }};
+    (@fields $state:ident, [ $( $field:ident : $ty:ty $( = $default:expr )? ),* ]) => {
+        if !$state.is_table(1) {
+            return $state.arg_error(1, "table expected");
+        }
+        // Fields are read first and the error is raised after they are dropped,
+        // because it doesn't run destructors
+        #[allow(unused_variables)]
+        let read = |state: &mut $crate::lua::State| -> Result<( $( $ty, )* ), String> {
+            $(
+            let $field: $ty = lua_kwargs_fn!(@field state, $field, $ty $(, $default)?);
+            )*
+            Ok(( $( $field, )* ))
+        };
+        let checked = read(&mut $state);
+        let ( $( $field, )* ) = match checked {
+            Ok(values) => values,
+            Err(message) => return $crate::raise_arg_error(&mut $state, 1, message),
+        };
+    };
+    (fn $name:ident { $( $field:ident : $ty:ty $( = $default:expr )? ),* $(,)? } -> $ret:ty $body:block) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            lua_kwargs_fn!(@fields state, [ $( $field : $ty $( = $default )? ),* ]);
+            let result: $ret = $body;
+            state.push(result);
+            1
+        }
+    };
+    (fn $name:ident { $( $field:ident : $ty:ty $( = $default:expr )? ),* $(,)? } $body:block) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            lua_kwargs_fn!(@fields state, [ $( $field : $ty $( = $default )? ),* ]);
+            $body;
+            0
+        }
+    };
+}
+
+/// Reads integer argument and evaluates the matching arm or raises argument error.
+#[macro_export]
+macro_rules! match_int {
+    ($state:ident, $arg:expr, { $( $pat:pat => $res:expr ),+ $(,)? }) => {
+        match $state.to_type::<$crate::lua::Integer>($arg) {
+            $( Some($pat) => $res, )+
+            _ => {
+                $state.arg_error($arg, "unexpected integer value");
+                unreachable!()
+            },
+        }
+    };
+}
+
+/// Parses string argument with `FromStr` or raises argument error with description of error.
+#[macro_export]
+macro_rules! parse_arg {
+    ($state:ident, $pos:expr, $ty:ty) => {{
+        // Numbers are rejected, because `to_str` would convert them in place
+        let parsed = match $state.type_of($pos) {
+            Some($crate::lua::Type::String) => match $state.to_str($pos) {
+                Some(s) => s.parse::<$ty>().map_err(|err| err.to_string()),
+                None => Err("string expected".to_owned()),
+            },
+            _ => Err("string expected".to_owned()),
+        };
+        match parsed {
+            Ok(value) => value,
+            Err(msg) => {
+                $crate::raise_arg_error(&mut $state, $pos, msg);
+                unreachable!()
+            },
+        }
+    }};
+}
+
+/// Creates table with weak keys in the registry to associate values with objects.
+#[macro_export]
+macro_rules! weak_registry {
+    ($state:ident) => {{
+        $state.get_field($crate::lua::REGISTRYINDEX, "lua_macros.weak_registry");
+        let exists = $state.is_table(-1);
+        $state.pop(1);
+        if !exists {
+            $state.new_table();
+            $state.new_table();
+            $state.push_string("k");
+            $state.set_field(-2, "__mode");
+            $state.set_metatable(-2);
+            $state.set_field($crate::lua::REGISTRYINDEX, "lua_macros.weak_registry");
+        }
+    }};
+}
+
+/// Associates value with object at `index` in table created by `weak_registry`.
+///
+/// Returns `false` if the table wasn't created.
+#[macro_export]
+macro_rules! assoc_set {
+    ($state:ident, $index:expr, $value:expr) => {{
+        let index = $state.abs_index($index);
+        $state.get_field($crate::lua::REGISTRYINDEX, "lua_macros.weak_registry");
+        let exists = $state.is_table(-1);
+        if exists {
+            $state.push_value(index);
+            $state.push($value);
+            $state.raw_set(-3);
+        }
+        $state.pop(1);
+        exists
+    }};
+}
+
+/// Reads value associated with object at `index` in table created by `weak_registry`.
+///
+/// Returns `None` if the table wasn't created.
+#[macro_export]
+macro_rules! assoc_get {
+    ($state:ident, $index:expr, $ty:ty) => {{
+        let index = $state.abs_index($index);
+        auto_cleanup!($state, {
+            $state.get_field($crate::lua::REGISTRYINDEX, "lua_macros.weak_registry");
+            if $state.is_table(-1) {
+                $state.push_value(index);
+                $state.raw_get(-2);
+                $state.to_type::<$ty>(-1)
+            } else {
+                None
+            }
+        })
+    }};
+}
+
+/// Declares config struct which reads fields from table.
+///
+/// Absent field is read from environment variable with `= env("NAME", default)`
+/// or takes default value with `= default`. Fields without defaults are required.
+#[macro_export]
+macro_rules! lua_config {
+    (@fields $name:ident, [$($done:tt)*]) => {
+        lua_config!(@impl $name, [$($done)*]);
+    };
+    (@fields $name:ident, [$($done:tt)*] , $($rest:tt)*) => {
+        lua_config!(@fields $name, [$($done)*] $($rest)*);
+    };
+    (@fields $name:ident, [$($done:tt)*] $field:ident : $ty:ty = env ( $var:expr , $default:expr ) $($rest:tt)*) => {
+        lua_config!(@fields $name, [$($done)* ($field, $ty, [$var], [$default])] $($rest)*);
+    };
+    (@fields $name:ident, [$($done:tt)*] $field:ident : $ty:ty = $default:expr) => {
+        lua_config!(@fields $name, [$($done)* ($field, $ty, [], [$default])]);
+    };
+    (@fields $name:ident, [$($done:tt)*] $field:ident : $ty:ty = $default:expr , $($rest:tt)*) => {
+        lua_config!(@fields $name, [$($done)* ($field, $ty, [], [$default])] $($rest)*);
+    };
+    (@fields $name:ident, [$($done:tt)*] $field:ident : $ty:ty) => {
+        lua_config!(@fields $name, [$($done)* ($field, $ty, [], [])]);
+    };
+    (@fields $name:ident, [$($done:tt)*] $field:ident : $ty:ty , $($rest:tt)*) => {
+        lua_config!(@fields $name, [$($done)* ($field, $ty, [], [])] $($rest)*);
+    };
+    (@impl $name:ident, [$( ($field:ident, $ty:ty, [$( $var:expr )*], [$( $default:expr )*]) )*]) => {
+        pub struct $name {
+            $( pub $field: $ty, )*
+        }
+
+        impl $crate::lua::FromLua for $name {
+            fn from_lua(state: &mut $crate::lua::State, index: $crate::lua::Index) -> Option<Self> {
+                if !state.is_table(index) {
+                    return None;
+                }
+                let index = state.abs_index(index);
+                $(
+                let $field: $ty = {
+                    state.get_field(index, stringify!($field));
+                    let value = if state.is_nil(-1) {
+                        None
+                    } else {
+                        match state.to_type::<$ty>(-1) {
+                            Some(value) => Some(value),
+                            None => {
+                                state.pop(1);
+                                return None;
+                            },
+                        }
+                    };
+                    state.pop(1);
+                    #[allow(unreachable_code)]
+                    let fallback = || -> Option<$ty> {
+                        $(
+                        let from_env = ::std::env::var($var).ok().and_then(|value| value.parse().ok());
+                        if from_env.is_some() {
+                            return from_env;
+                        }
+                        )*
+                        $( return Some($default.into()); )*
+                        None
+                    };
+                    match value.or_else(fallback) {
+                        Some(value) => value,
+                        None => {
+                            return None;
+                        },
+                    }
+                };
+                )*
+                Some($name {
+                    $( $field: $field, )*
+                })
+            }
+        }
+    };
+    ($name:ident { $($fields:tt)* }) => {
+        lua_config!(@fields $name, [] $($fields)*);
+    };
+}
+
+/// Generates Lua function with the name of userdata's method which returns `Result`.
+///
+/// `Ok` value is returned, `Err` raises error with its description.
+#[macro_export]
+macro_rules! lua_try_method {
+    (@shim $ud:ident, $method:ident, [$( $arg:ident : $aty:ty ),*], $call:ident, $ok:ty) => {
+        unsafe extern "C" fn $method(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            let (_, $( $arg, )*) = convert_arguments!(state, _ $(, $aty)*)
+                .unwrap_or_else(|pos| {
+                    state.arg_error(pos, "wrong argument");
+                    unreachable!()
+                });
+            let result = match state.test_userdata_typed::<$ud>(1, $ud::meta_name()) {
+                Some(this) => this.$call($( $arg ),*),
+                None => {
+                    state.arg_error(1, concat!("`", stringify!($ud), "` expected"));
+                    unreachable!()
+                },
+            };
+            match result {
+                Ok(value) => {
+                    let value: $ok = value;
+                    state.push(value);
+                    1
+                },
+                Err(err) => {
+                    // Error doesn't run destructors, so free the message before it
+                    let msg = err.to_string();
+                    drop(err);
+                    state.push_string(&msg);
+                    drop(msg);
+                    state.error()
+                },
+            }
+        }
+    };
+    ($ud:ident { fn $method:ident (&self $(, $arg:ident : $aty:ty)*) -> Result<$ok:ty, $err:ty> $(;)* }) => {
+        lua_try_method!(@shim $ud, $method, [$( $arg : $aty ),*], $method, $ok);
+    };
+    ($ud:ident { fn $method:ident (&mut self $(, $arg:ident : $aty:ty)*) -> Result<$ok:ty, $err:ty> $(;)* }) => {
+        lua_try_method!(@shim $ud, $method, [$( $arg : $aty ),*], $method, $ok);
+    };
+}
+
+/// Makes wrapper to read array of booleans (or integers `0` and `1`) to `BitVec`.
+/// Requires `bitset` feature.
+#[cfg(feature = "bitset")]
+#[macro_export]
+macro_rules! lua_bitset_type {
+    ($name:ident) => {
+        pub struct $name(pub $crate::bitvec::vec::BitVec);
+
+        impl $crate::lua::FromLua for $name {
+            fn from_lua(state: &mut $crate::lua::State, index: $crate::lua::Index) -> Option<Self> {
+                if !state.is_table(index) {
+                    return None;
+                }
+                let index = state.abs_index(index);
+                let len = state.raw_len(index) as $crate::lua::Integer;
+                let mut bits = $crate::bitvec::vec::BitVec::with_capacity(len as usize);
+                for idx in 1..len + 1 {
+                    state.geti(index, idx);
+                    let bit = match state.type_of(-1) {
+                        Some($crate::lua::Type::Boolean) => Some(state.to_bool(-1)),
+                        Some($crate::lua::Type::Number) => match state.to_type::<$crate::lua::Integer>(-1) {
+                            Some(0) => Some(false),
+                            Some(1) => Some(true),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    state.pop(1);
+                    match bit {
+                        Some(bit) => bits.push(bit),
+                        None => {
+                            return None;
+                        },
+                    }
+                }
+                Some($name(bits))
+            }
+        }
+
+        impl $crate::lua::ToLua for $name {
+            fn to_lua(&self, state: &mut $crate::lua::State) {
+                let $name(ref bits) = *self;
+                state.create_table(bits.len() as i32, 0);
+                for (idx, bit) in bits.iter().by_vals().enumerate() {
+                    state.push_bool(bit);
+                    state.raw_seti(-2, idx as $crate::lua::Integer + 1);
+                }
+            }
+        }
+    };
+}
+
+/// Wraps Lua function with `RateLimiter` kept in the registry of state.
+///
+/// Calls over the limit raise error.
+#[macro_export]
+macro_rules! lua_rate_limited_fn {
+    ($name:ident, max_per_sec = $max:expr, $func:expr) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let key = concat!("lua_macros.rate_limit.", stringify!($name));
+            let mut state = $crate::lua::State::from_ptr(ls);
+            state.get_field($crate::lua::REGISTRYINDEX, key);
+            if state.is_nil(-1) {
+                state.pop(1);
+                let pointer = state.new_userdata_typed::<$crate::RateLimiter>();
+                ::std::ptr::write(pointer, $crate::RateLimiter::new($max as f64));
+                state.push_value(-1);
+                state.set_field($crate::lua::REGISTRYINDEX, key);
+            }
+            let allowed = state.to_userdata_typed::<$crate::RateLimiter>(-1)
+                .map(|limiter| limiter.take())
+                .unwrap_or(false);
+            state.pop(1);
+            if !allowed {
+                state.push_string(concat!("rate limit exceeded for `", stringify!($name), "`"));
+                return state.error();
+            }
+            $func(ls)
+        }
+    };
+}
+
+/// Pushes array with items of iterator without collecting them.
+///
+/// Lower bound of `size_hint` (exact for `ExactSizeIterator`) preallocates the array.
+#[macro_export]
+macro_rules! push_iter_array {
+    ($state:ident, $iter:expr) => {{
+        let iter = $iter.into_iter();
+        let (hint, _) = iter.size_hint();
+        $state.create_table(hint as $crate::lua::libc::c_int, 0);
+        let mut idx = 0;
+        for item in iter {
+            idx += 1; // Starts from 1 too
+            $state.push(item);
+            $state.raw_seti(-2, idx);
+        }
+    }};
+}
+
+/// Declares Lua function which checks count of arguments before the body.
+///
+/// Arity is the count of listed types, arguments are converted by `convert_arguments`
+/// and passed as a tuple. The body is a closure which takes `&mut State` and the tuple
+/// and returns `Ok` with count of results or `Err` with position of wrong argument.
+#[macro_export]
+macro_rules! lua_fn_arity {
+    ($name:ident, [ $( $slot:ty ),+ ], $body:expr) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let body: fn(&mut $crate::lua::State, ( $( $slot, )+ )) -> Result<$crate::lua::libc::c_int, $crate::lua::Index> = $body;
+            let mut state = $crate::lua::State::from_ptr(ls);
+            let arity = [ $( stringify!($slot) ),+ ].len() as $crate::lua::Index;
+            let top = state.get_top();
+            if top != arity {
+                let msg = format!("`{}` expects {} arguments, got {}", stringify!($name), arity, top);
+                state.push_string(&msg);
+                drop(msg);
+                return state.error();
+            }
+            let result = convert_arguments!(state, $( $slot ),+).and_then(|args| body(&mut state, args));
+            match result {
+                Ok(results) => results,
+                Err(pos) => state.arg_error(pos, "wrong argument"),
+            }
+        }
+    };
+}
+
+/// Converts userdata to boxed trait object with `TypeRegistry`.
+#[macro_export]
+macro_rules! to_trait_object {
+    ($state:ident, $index:expr, $registry:expr, $tr:ty) => {{
+        let registry: &$crate::TypeRegistry<$tr> = &$registry;
+        registry.convert(&mut $state, $index)
+    }};
+}
+
+/// Declares Lua function which yields the coroutine.
+///
+/// The first block pushes values to yield and returns their count. The second block
+/// is the continuation which runs when the coroutine is resumed; the values passed to
+/// resume are on top of the stack and it returns count of results.
+#[macro_export]
+macro_rules! lua_yielding_fn {
+    (fn $name:ident ($state:ident) $before:block resume ($rstate:ident) $after:block) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            unsafe extern "C" fn continuation(ls: *mut $crate::lua::ffi::lua_State,
+                                              _status: $crate::lua::libc::c_int,
+                                              _ctx: $crate::lua::ffi::lua_KContext)
+                                              -> $crate::lua::libc::c_int {
+                #[allow(unused_mut)]
+                let mut $rstate = $crate::lua::State::from_ptr(ls);
+                $after
+            }
+            let count: $crate::lua::libc::c_int = {
+                #[allow(unused_mut)]
+                let mut $state = $crate::lua::State::from_ptr(ls);
+                $before
+            };
+            $crate::lua::ffi::lua_yieldk(ls, count, 0, Some(continuation))
+        }
+    };
+}
+
+/// Declares enum with data which is read from table by its tag field and pushed as such table.
+///
+/// Value of the tag is name of variant in lower case.
+///
+/// Optional flags:
+/// * `constructors` - adds `attach` which registers global table with constructors of variants,
+///   they take fields in order of declaration.
+#[macro_export]
+macro_rules! lua_variant_enum {
+    (@args $state:ident, [ ]) => {
+        ()
+    };
+    (@args $state:ident, [ $( $fty:ty ),+ ]) => {
+        convert_arguments!($state, $( $fty ),+)
+            .unwrap_or_else(|pos| {
+                $state.arg_error(pos, "wrong field");
+                unreachable!()
+            })
+    };
+    (@constructors $name:ident, { $( $variant:ident { $( $field:ident : $fty:ty ),* } ),* }) => {
+        impl $name {
+            pub fn attach(state: &mut $crate::lua::State) {
+                state.new_table();
+                $({
+                    unsafe extern "C" fn constructor(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+                        let mut state = $crate::lua::State::from_ptr(ls);
+                        let ($( $field, )*) = lua_variant_enum!(@args state, [ $( $fty ),* ]);
+                        state.push($name::$variant { $( $field: $field ),* });
+                        1
+                    }
+                    state.push_fn(Some(constructor));
+                    state.set_field(-2, stringify!($variant));
+                })*
+                state.set_global(stringify!($name));
+            }
+        }
+    };
+    ($(#[$meta:meta])* $name:ident, tag = $tag:tt {
+        $( $variant:ident { $( $field:ident : $fty:ty ),* $(,)? } ),* $(,)?
+    } $(, $flag:ident)*) => {
+        $(
+        lua_variant_enum!(@ $flag $name, { $( $variant { $( $field : $fty ),* } ),* });
+        )*
+
+        $(#[$meta])*
+        pub enum $name {
+            $( $variant { $( $field: $fty ),* }, )*
+        }
+
+        impl $crate::lua::FromLua for $name {
+            fn from_lua(state: &mut $crate::lua::State, index: $crate::lua::Index) -> Option<Self> {
+                if !state.is_table(index) {
+                    return None;
+                }
+                let index = state.abs_index(index);
+                state.get_field(index, $tag);
+                let tag = state.to_type::<String>(-1);
+                state.pop(1);
+                let tag = tag?;
+                $(
+                if tag == stringify!($variant).to_lowercase() {
+                    $(
+                    state.get_field(index, stringify!($field));
+                    let $field = state.to_type::<$fty>(-1);
+                    state.pop(1);
+                    let $field = $field?;
+                    )*
+                    return Some($name::$variant { $( $field: $field ),* });
+                }
+                )*
+                None
+            }
+        }
+
+        impl $crate::lua::ToLua for $name {
+            fn to_lua(&self, state: &mut $crate::lua::State) {
+                #[allow(unused_imports)]
+                use $crate::lua::ToLua;
+                match *self {
+                    $(
+                    $name::$variant { $( ref $field ),* } => {
+                        state.new_table();
+                        state.push_string(&stringify!($variant).to_lowercase());
+                        state.set_field(-2, $tag);
+                        $(
+                        $field.to_lua(state);
+                        state.set_field(-2, stringify!($field));
+                        )*
+                    },
+                    )*
+                }
+            }
+        }
+    };
+}
+
+/// Runs block with a new state which memory is counted by `AllocTracker`.
+///
+/// Returns net bytes allocated by the block after full garbage collection and
+/// panics if they exceed the threshold.
+#[macro_export]
+macro_rules! with_tracking_allocator {
+    ($state:ident, threshold = $threshold:expr, $body:block) => {{
+        let tracker = Box::new($crate::AllocTracker::new());
+        let net = {
+            #[allow(unused_mut)]
+            let mut $state = unsafe { tracker.new_state() };
+            let baseline = tracker.allocated();
+            $body;
+            $state.gc($crate::lua::GcOption::Collect, 0);
+            let net = tracker.allocated() - baseline;
+            unsafe {
+                $crate::lua::ffi::lua_close($state.as_ptr());
+            }
+            net
+        };
+        let threshold: isize = $threshold;
+        if net > threshold {
+            panic!("Lua allocated {} bytes more than threshold {}.", net - threshold, threshold);
+        }
+        net
+    }};
+}
+
+/// Wraps coroutine to `LuaFuture`.
+///
+/// The thread object (pushed by `new_thread`) has to be on top of `state`.
+#[macro_export]
+macro_rules! lua_future {
+    ($state:ident, $thread:expr) => {
+        $crate::LuaFuture::new(&mut $state, $thread)
+    };
+}
+
+/// Sets global `metrics` table which forwards to `MetricsSink`.
+#[macro_export]
+macro_rules! lua_metrics {
+    ($state:ident, $sink:expr) => {
+        $crate::register_metrics(&mut $state, Box::new($sink))
+    };
+}
+
+/// Reads value to type which implements `Deserialize`. Requires `serde` feature.
+#[cfg(feature = "serde")]
+#[macro_export]
+macro_rules! from_lua_serde {
+    ($state:ident, $index:expr, $t:ty) => {
+        $crate::de::from_lua::<$t>(&mut $state, $index)
+    };
+}
+
+/// Pushes value of type which implements `Serialize`. Requires `serde` feature.
+#[cfg(feature = "serde")]
+#[macro_export]
+macro_rules! to_lua_serde {
+    ($state:ident, $value:expr) => {
+        $crate::ser::to_lua(&mut $state, $value)
+    };
+}
+
+/// Declares Lua function which takes the context kept by `set_context` as the first parameter.
+///
+/// Other parameters are arguments of the function converted by `convert_arguments`,
+/// the context could be the only parameter.
+#[macro_export]
+macro_rules! lua_ctx_fn {
+    (@args $state:ident, []) => {};
+    (@args $state:ident, [ $( $arg:ident : $aty:ty ),+ ]) => {
+        let ($( $arg, )+) = convert_arguments!($state, $( $aty ),+)
+            .unwrap_or_else(|pos| {
+                $state.arg_error(pos, "wrong argument");
+                unreachable!()
+            });
+    };
+    (@context $state:ident, $key:expr, $cty:ty) => {{
+        $state.get_field($crate::lua::REGISTRYINDEX, $key);
+        let ctx = $state.test_userdata_typed::<$cty>(-1, &$crate::context_meta::<$cty>())
+            .map(|ctx| ctx as *mut $cty);
+        $state.pop(1);
+        match ctx {
+            Some(ctx) => &mut *ctx,
+            None => {
+                $state.push_string(concat!("context `", stringify!($cty), "` is not set"));
+                $state.error();
+                unreachable!()
+            },
+        }
+    }};
+    ($key:expr, fn $name:ident ($ctx:ident : &mut $cty:ty $(, $arg:ident : $aty:ty)*) -> $ret:ty $body:block) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            lua_ctx_fn!(@args state, [ $( $arg : $aty ),* ]);
+            let $ctx: &mut $cty = lua_ctx_fn!(@context state, $key, $cty);
+            let result: $ret = $body;
+            state.push(result);
+            1
+        }
+    };
+    ($key:expr, fn $name:ident ($ctx:ident : &mut $cty:ty $(, $arg:ident : $aty:ty)*) $body:block) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            lua_ctx_fn!(@args state, [ $( $arg : $aty ),* ]);
+            let $ctx: &mut $cty = lua_ctx_fn!(@context state, $key, $cty);
+            $body;
+            0
+        }
+    };
+}
+
+/// Declares struct of arrays which reads an array of tables by fields to separate vectors.
+#[macro_export]
+macro_rules! lua_columnar_type {
+    ($name:ident { $( $field:ident : $ty:ty ),+ $(,)? }) => {
+        pub struct $name {
+            $( pub $field: ::std::vec::Vec<$ty>, )+
+        }
+
+        impl $crate::lua::FromLua for $name {
+            fn from_lua(state: &mut $crate::lua::State, index: $crate::lua::Index) -> Option<Self> {
+                if !state.is_table(index) {
+                    return None;
+                }
+                let index = state.abs_index(index);
+                let len = state.raw_len(index) as $crate::lua::Integer;
+                $( let mut $field = ::std::vec::Vec::with_capacity(len as usize); )+
+                for idx in 1..len + 1 {
+                    state.geti(index, idx);
+                    if !state.is_table(-1) {
+                        state.pop(1);
+                        return None;
+                    }
+                    $(
+                    state.get_field(-1, stringify!($field));
+                    let value = state.to_type::<$ty>(-1);
+                    state.pop(1);
+                    match value {
+                        Some(value) => $field.push(value),
+                        None => {
+                            state.pop(1);
+                            return None;
+                        },
+                    }
+                    )+
+                    state.pop(1);
+                }
+                Some($name {
+                    $( $field: $field, )+
+                })
+            }
+        }
+    };
+}
+
+/// Declares Lua function which takes any count of arguments as JSON values. Requires `json` feature.
+#[cfg(feature = "json")]
+#[macro_export]
+macro_rules! lua_json_args_fn {
+    (fn $name:ident ($args:ident) $body:block) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            let $args = match $crate::json::args_to_json(&mut state) {
+                Ok(args) => args,
+                Err((position, err)) => {
+                    let msg = err.to_string();
+                    drop(err);
+                    return $crate::raise_arg_error(&mut state, position, msg);
+                },
+            };
+            $body;
+            0
+        }
+    };
+}
+
+/// Adds property `name` bound to `field` of userdata to handlers of its metatable.
+///
+/// Expects metatable which is attached already. Handlers set before (or methods table)
+/// serve other keys, so calls compose.
+#[macro_export]
+macro_rules! lua_field {
+    ($state:ident, $ud:ident, $name:expr, $field:ident : $ty:ty) => {{
+        fn is_key(state: &mut $crate::lua::State) -> bool {
+            state.type_of(2) == Some($crate::lua::Type::String) && state.to_str(2) == Some($name)
+        }
+        unsafe extern "C" fn index(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            if is_key(&mut state) {
+                let value = state.test_userdata_typed::<$ud>(1, $ud::meta_name())
+                    .map(|this| this.$field.clone());
+                match value {
+                    Some(value) => {
+                        let value: $ty = value;
+                        state.push(value);
+                    },
+                    None => state.push_nil(),
+                }
+                return 1;
+            }
+            $crate::call_prev_index(&mut state, $crate::lua::ffi::lua_upvalueindex(1));
+            1
+        }
+        unsafe extern "C" fn newindex(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            if is_key(&mut state) {
+                let value = match state.to_type::<$ty>(3) {
+                    Some(value) => value,
+                    None => {
+                        state.arg_error(3, concat!("`", stringify!($ty), "` expected"));
+                        unreachable!()
+                    },
+                };
+                if let Some(this) = state.test_userdata_typed::<$ud>(1, $ud::meta_name()) {
+                    this.$field = value;
+                }
+                return 0;
+            }
+            if !$crate::call_prev_newindex(&mut state, $crate::lua::ffi::lua_upvalueindex(1)) {
+                state.push_string(concat!("can't set field of `", stringify!($ud), "`"));
+                state.error();
+            }
+            0
+        }
+        // Previous handlers become upvalues of new ones
+        $state.get_metatable_from_registry($ud::meta_name());
+        $state.get_field(-1, "__index");
+        $state.push_closure(Some(index), 1);
+        $state.set_field(-2, "__index");
+        $state.get_field(-1, "__newindex");
+        $state.push_closure(Some(newindex), 1);
+        $state.set_field(-2, "__newindex");
+        $state.pop(1);
+    }};
+}
+
+/// Loads chunk to `CompiledChunk` which could be run many times.
+#[macro_export]
+macro_rules! compile_chunk {
+    ($state:ident, $source:expr) => {
+        $crate::CompiledChunk::compile(&mut $state, $source)
+    };
+}
+
+/// Reads items of array at `index` to a tuple. Missing (`nil`) items take defaults.
+///
+/// Returns `Err` with position of item which is missing without default or can't be
+/// converted, or `Err(0)` if the value is not a table.
+#[macro_export]
+macro_rules! lua_positional {
+    ($state:ident, $index:expr, [ $( $ty:ty $( = $default:expr )? ),+ $(,)? ]) => {{
+        let index = $state.abs_index($index);
+        let mut position: $crate::lua::Index = 0;
+        let mut read = || -> Result<_, $crate::lua::Index> {
+            if !$state.is_table(index) {
+                return Err(0);
+            }
+            Ok(($( {
+                position += 1;
+                #[allow(unreachable_code)]
+                let default = || -> Option<$ty> {
+                    $( return Some($default.into()); )?
+                    None
+                };
+                $state.geti(index, position as $crate::lua::Integer);
+                let value = if $state.is_nil(-1) {
+                    default()
+                } else {
+                    $state.to_type::<$ty>(-1)
+                };
+                $state.pop(1);
+                value.ok_or(position)?
+            }, )+))
+        };
+        read()
+    }};
+}
+
+/// Checks that object has all methods of the list. Returns `Err` with the first missing method.
+#[macro_export]
+macro_rules! require_interface {
+    ($state:ident, $index:expr, [ $( $method:expr ),* $(,)? ]) => {
+        $crate::require_interface(&mut $state, $index, &[ $( $method ),* ])
+    };
+}
+
+/// Declares struct which is read from table by names of fields and pushed as table back.
+///
+/// Reading fails if a field is missing or has wrong type. Field declared as `name: Type = expr`
+/// is optional, the expression is evaluated if the field is missing (or `nil`).
+/// Field declared as `name from "a.b": Type` is read from (and pushed to) nested path.
+///
+/// Form `Name { fields } + extras: Map` puts pairs with string keys which are not
+/// fields to map `extras` (any map with `insert(String, V)` method and `Default`).
+/// Pairs of `extras` are set to pushed table too.
+#[macro_export]
+macro_rules! lua_struct {
+    (@value $state:ident, $ty:ty, [ ]) => {
+        $state.to_type::<$ty>(-1)
+    };
+    (@value $state:ident, $ty:ty, [ $default:expr ]) => {
+        if $state.is_nil(-1) {
+            Some($default)
+        } else {
+            $state.to_type::<$ty>(-1)
+        }
+    };
+    (@key $field:ident, [ ]) => {
+        stringify!($field)
+    };
+    (@key $field:ident, [ $path:literal ]) => {
+        $path
+    };
+    (@from_lua $name:ident, [ $( $field:ident [ $( $path:literal )? ] : $ty:ty [ $( $default:expr )? ] ),* ],
+     [ $( $extras:ident : $map:ty )* ]) => {
+        impl $crate::lua::FromLua for $name {
+            fn from_lua(state: &mut $crate::lua::State, index: $crate::lua::Index) -> Option<Self> {
+                if !state.is_table(index) {
+                    return None;
+                }
+                let index = state.abs_index(index);
+                $(
+                let $field: $ty = {
+                    $crate::get_path(state, index, lua_struct!(@key $field, [ $( $path )? ]));
+                    let value = lua_struct!(@value state, $ty, [ $( $default )? ]);
+                    state.pop(1);
+                    value?
+                };
+                )*
+                // Nested paths take whole tables of their first keys
+                #[allow(unused_variables)]
+                let known: &[&str] = &[$( lua_struct!(@key $field, [ $( $path )? ]) ),*];
+                $(
+                let mut $extras: $map = ::std::default::Default::default();
+                state.push_nil();
+                while state.next(index) {
+                    // Don't use `to_str` for numbers, because it changes the key and breaks `next`
+                    let key = if state.type_of(-2) == Some($crate::lua::Type::String) {
+                        state.to_str(-2).map(str::to_owned)
+                    } else {
+                        None
+                    };
+                    match key {
+                        Some(ref key) if known.iter().any(|k| k.split('.').next() == Some(key.as_str())) => {
+                        },
+                        Some(key) => {
+                            match $crate::lua::FromLua::from_lua(state, -1) {
+                                Some(value) => {
+                                    $extras.insert(key, value);
+                                },
+                                None => {
+                                    state.pop(2); // Pop `key` and `value`, because `next` call returned `true`
+                                    return None;
+                                },
+                            }
+                        },
+                        None => {
+                        },
+                    }
+                    state.pop(1); // Pop `value` only
+                }
+                )*
+                Some($name {
+                    $( $field: $field, )*
+                    $( $extras: $extras, )*
+                })
+            }
+        }
+    };
+    (@to_lua $name:ident, [ $( $field:ident [ $( $path:literal )? ] ),* ], [ $( $extras:ident )* ]) => {
+        impl $crate::lua::ToLua for $name {
+            fn to_lua(&self, state: &mut $crate::lua::State) {
+                state.new_table();
+                $(
+                $crate::lua::ToLua::to_lua(&self.$field, state);
+                $crate::set_path(state, -2, lua_struct!(@key $field, [ $( $path )? ]));
+                )*
+                $(
+                for (key, value) in &self.$extras {
+                    $crate::lua::ToLua::to_lua(value, state);
+                    state.set_field(-2, key);
+                }
+                )*
+            }
+        }
+    };
+    ($(#[$attr:meta])* $name:ident {
+        $( $field:ident $( from $path:literal )? : $ty:ty $( = $default:expr )? ),* $(,)?
+    } + $extras:ident : $map:ty) => {
+        $(#[$attr])*
+        pub struct $name {
+            $( pub $field: $ty, )*
+            pub $extras: $map,
+        }
 
+        lua_struct!(@from_lua $name, [ $( $field [ $( $path )? ] : $ty [ $( $default )? ] ),* ], [ $extras : $map ]);
+        lua_struct!(@to_lua $name, [ $( $field [ $( $path )? ] ),* ], [ $extras ]);
+    };
+    ($(#[$attr:meta])* $name:ident {
+        $( $field:ident $( from $path:literal )? : $ty:ty $( = $default:expr )? ),* $(,)?
+    }) => {
+        $(#[$attr])*
+        pub struct $name {
+            $( pub $field: $ty, )*
+        }
 
-pub extern crate lua;
+        lua_struct!(@from_lua $name, [ $( $field [ $( $path )? ] : $ty [ $( $default )? ] ),* ], [ ]);
+        lua_struct!(@to_lua $name, [ $( $field [ $( $path )? ] ),* ], [ ]);
+    };
+}
 
-/// Clean up stack for the scope.
+/// Sets global function `name` which writes its arguments converted like `tostring` does to `writer`.
+///
+/// The writer is kept in the registry by `set_context`.
 #[macro_export]
-macro_rules! auto_cleanup {
-    ($state:ident, $b:block) => {{
-        let top = $state.get_top();
-        let result = $b;
-        $state.set_top(top);
-        result
+macro_rules! lua_writer_fn {
+    ($state:ident, $name:expr, $writer:expr) => {{
+        unsafe extern "C" fn write(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            let top = state.get_top();
+            let mut text = String::new();
+            for idx in 1..top + 1 {
+                $crate::lua::ffi::luaL_tolstring(ls, idx, ::std::ptr::null_mut());
+                text.push_str(state.to_str(-1).unwrap_or(""));
+                state.pop(1);
+            }
+            let key = concat!("lua_macros.writer.", $name);
+            let result = $crate::with_context(&mut state, key, |writer: &mut Box<dyn ::std::io::Write>| {
+                writer.write_all(text.as_bytes())
+            });
+            drop(text);
+            match result {
+                Some(Ok(())) => 0,
+                Some(Err(err)) => {
+                    // Error doesn't run destructors, so free the message before it
+                    let msg = err.to_string();
+                    drop(err);
+                    state.push_string(&msg);
+                    drop(msg);
+                    state.error()
+                },
+                None => {
+                    state.push_string(concat!("writer of `", $name, "` is not set"));
+                    state.error()
+                },
+            }
+        }
+        let writer: Box<dyn ::std::io::Write> = Box::new($writer);
+        $crate::set_context(&mut $state, concat!("lua_macros.writer.", $name), writer);
+        $state.push_fn(Some(write));
+        $state.set_global($name);
     }};
 }
 
-/// Convert arguments using `FromLua` trait.
+/// Replaces global `print` with function which joins its arguments converted like `tostring`
+/// does with tabs and logs them at `level` with target `lua`. Requires `log` feature.
+#[cfg(feature = "log")]
 #[macro_export]
-macro_rules! convert_arguments {
-    (@strict $strict:expr, $state:ident, $($from:tt),+) => {{
-        use $crate::lua::Index;
-        let names = [$(stringify!($from),)+];
-        let quantity = names.len() as Index;
-        let top = $state.get_top();
-        auto_cleanup!($state, {
-            let mut collect = || {
-                let base = $state.get_top() - quantity;
-                if base < 0 {
-                    return Err(top + 1); // +1 because next arg expected
-                }
-                if $strict && base > 0 {
-                    return Err(top);
-                }
-                let mut position = 0;
-                let result = ($({
-                    position += 1;
-                    convert_arguments!(@unpack $from, $state, base, position)
-                },)+);
-                Ok(result)
-            };
-            collect()
-        })
+macro_rules! capture_print {
+    ($state:ident, level = $level:ident) => {{
+        unsafe extern "C" fn print(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            let top = state.get_top();
+            let mut parts = Vec::with_capacity(top as usize);
+            for idx in 1..top + 1 {
+                $crate::lua::ffi::luaL_tolstring(ls, idx, ::std::ptr::null_mut());
+                parts.push(state.to_str(-1).unwrap_or("").to_owned());
+                state.pop(1);
+            }
+            $crate::log::log!(target: "lua", $crate::log::Level::$level, "{}", parts.join("\t"));
+            0
+        }
+        $state.push_fn(Some(print));
+        $state.set_global("print");
     }};
-    (@unpack _, $state:ident, $base:expr, $position:expr) => {()};
-    (@unpack $from:ty, $state:ident, $base:expr, $position:expr) => {{
-        let opt = $state.to_type::<$from>($base + $position);
-        match opt {
-            Some(v) => v,
-            None => {
-                return Err($position);
-            },
+}
+
+/// Sets global function `name(event, payload)` which sends pair of event's name and
+/// payload converted to `LuaValue` to `sender` of `std::sync::mpsc` channel.
+///
+/// The sender is kept in the registry by `set_context`.
+#[macro_export]
+macro_rules! lua_channel_sender {
+    ($state:ident, $name:expr, $sender:expr) => {{
+        type Sender = ::std::sync::mpsc::Sender<(String, $crate::LuaValue)>;
+        unsafe extern "C" fn emit(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            if state.get_top() < 2 {
+                state.push_nil(); // Payload is optional
+            }
+            let (event, payload) = match convert_arguments!(state, String, $crate::LuaValue) {
+                Ok(args) => args,
+                Err(1) => return state.arg_error(1, "name of event expected"),
+                Err(pos) => return state.arg_error(pos, "plain value expected"),
+            };
+            let key = concat!("lua_macros.channel.", $name);
+            let sent = $crate::with_context(&mut state, key, move |sender: &mut Sender| {
+                sender.send((event, payload)).is_ok()
+            });
+            match sent {
+                Some(true) => 0,
+                Some(false) => {
+                    state.push_string(concat!("receiver of `", $name, "` is closed"));
+                    state.error()
+                },
+                None => {
+                    state.push_string(concat!("sender of `", $name, "` is not set"));
+                    state.error()
+                },
+            }
         }
+        let sender: Sender = $sender;
+        $crate::set_context(&mut $state, concat!("lua_macros.channel.", $name), sender);
+        $state.push_fn(Some(emit));
+        $state.set_global($name);
     }};
-    ($state:ident, $($from:tt),+) =>
-        (convert_arguments!(@strict true, $state, $($from),+));
 }
 
-/// Makes wrapper to read table to hash map.
+/// Sets `_VERSION` string and `_CAPABILITIES` set (table with `true` values) to global module table.
 ///
-/// This macro add wrapper struct, because impossible to implement `FromLua` to `HashMap`
-/// because they are from other crates.
+/// The table is created if module is not set yet.
 #[macro_export]
-macro_rules! lua_table_type {
+macro_rules! lua_module_info {
+    ($state:ident, $name:expr, version = $version:expr, capabilities = [ $( $cap:expr ),* $(,)? ]) => {{
+        $state.get_global($name);
+        if !$state.is_table(-1) {
+            $state.pop(1);
+            $state.new_table();
+            $state.push_value(-1);
+            $state.set_global($name);
+        }
+        $state.push_string($version);
+        $state.set_field(-2, "_VERSION");
+        $state.new_table();
+        $(
+        $state.push_bool(true);
+        $state.set_field(-2, $cap);
+        )*
+        $state.set_field(-2, "_CAPABILITIES");
+        $state.pop(1);
+    }};
+}
+
+/// Makes wrapper to read table to `BTreeMap`, which keeps keys sorted.
+#[macro_export]
+macro_rules! lua_ordered_table_type {
     ($name:ident < $key:ty , $val:ty >) => {
-        pub struct $name(pub ::std::collections::HashMap<$key, $val>);
+        pub struct $name(pub ::std::collections::BTreeMap<$key, $val>);
 
-        impl $crate::lua::FromLua for $name {
+        impl $crate::lua::FromLua for $name where $key: Ord {
             fn from_lua(state: &mut $crate::lua::State, index: $crate::lua::Index) -> Option<Self> {
-                if !state.is_table(index) {
-                    return None;
-                }
-                let mut map = ::std::collections::HashMap::new();
-                let index = state.abs_index(index);
-                state.push_nil();
-                while state.next(index) {
-                    // Non-strict, because this macro pushes to stack additional values
-                    if let Ok((name, value)) = convert_arguments!(@strict false, state, $key, $val) {
-                        map.insert(name, value);
-                        state.pop(1); // Pop `key` only
-                    } else {
-                        state.pop(2); // Pop `key` and `value`, because `next` call returned `true`
-                        return None;
-                    }
+                lua_table_type!(@read_map state, index, $key, $val, ::std::collections::BTreeMap<$key, $val>)
+                    .map($name)
+            }
+        }
+
+        impl $crate::lua::ToLua for $name where $key: $crate::lua::ToLua, $val: $crate::lua::ToLua {
+            fn to_lua(&self, state: &mut $crate::lua::State) {
+                let $name(ref map) = *self;
+                state.create_table(0, map.len() as $crate::lua::libc::c_int);
+                for (key, value) in map {
+                    $crate::lua::ToLua::to_lua(key, state);
+                    $crate::lua::ToLua::to_lua(value, state);
+                    state.raw_set(-3);
                 }
-                Some($name(map))
             }
         }
     };
 }
 
-/// Makes wrapper to read table to array.
+/// Makes wrapper to read array of tables to vector of hash maps.
+///
+/// Every element of the array must be a table with keys and values of given types.
 #[macro_export]
-macro_rules! lua_array_type {
-    ($name:ident < $val:ty >) => {
-        pub struct $name(pub ::std::vec::Vec<$val>);
+macro_rules! lua_records_type {
+    ($name:ident < $key:ty , $val:ty >) => {
+        pub struct $name(pub ::std::vec::Vec<::std::collections::HashMap<$key, $val>>);
 
         impl $crate::lua::FromLua for $name {
             fn from_lua(state: &mut $crate::lua::State, index: $crate::lua::Index) -> Option<Self> {
                 if !state.is_table(index) {
                     return None;
                 }
-                let mut vec = ::std::vec::Vec::new();
                 let index = state.abs_index(index);
-                for idx in 1.. {
+                let len = state.raw_len(index) as $crate::lua::Integer;
+                let mut rows = ::std::vec::Vec::with_capacity(len as usize);
+                for idx in 1..len + 1 {
                     state.geti(index, idx);
-                    if state.is_nil(-1) {
-                        state.pop(1);
-                        break;
-                    }
-                    if let Ok((value,)) = convert_arguments!(@strict false, state, $val) {
-                        vec.push(value);
-                        state.pop(1);
-                    } else {
-                        state.pop(1);
-                        return None;
-                    }
+                    let row = lua_table_type!(@read state, -1, $key, $val);
+                    state.pop(1);
+                    rows.push(row?);
                 }
-                Some($name(vec))
+                Some($name(rows))
             }
         }
 
-        impl $crate::lua::ToLua for $name {
+        impl $crate::lua::ToLua for $name where $key: $crate::lua::ToLua, $val: $crate::lua::ToLua {
             fn to_lua(&self, state: &mut $crate::lua::State) {
-                let $name(ref vec) = *self;
-                state.new_table();
+                let $name(ref rows) = *self;
+                state.create_table(rows.len() as $crate::lua::libc::c_int, 0);
                 let mut idx = 0;
-                for item in vec {
+                for row in rows {
+                    state.create_table(0, row.len() as $crate::lua::libc::c_int);
+                    for (key, value) in row {
+                        $crate::lua::ToLua::to_lua(key, state);
+                        $crate::lua::ToLua::to_lua(value, state);
+                        state.raw_set(-3);
+                    }
                     idx += 1; // Starts from 1 too
-                    item.to_lua(state);
                     state.raw_seti(-2, idx);
                 }
             }
@@ -290,57 +7940,550 @@ macro_rules! lua_array_type {
     };
 }
 
-/// Add userdata's methods to user's type.
+/// Pushes value encoded by `Codec`.
 #[macro_export]
-macro_rules! lua_userdata {
-    ($ud:ident $(, $field:expr => $func:ident )*) => {
-        impl $ud {
-            pub fn meta_name() -> &'static str {
-                concat!(stringify!($ud), ".Rust")
-            }
+macro_rules! push_codec {
+    ($state:ident, $value:expr) => {
+        $crate::Codec::encode($value, &mut $state)
+    };
+}
 
-            pub fn attach(state: &mut State) {
-                let created = state.new_metatable($ud::meta_name());
-                $(
-                state.push_fn(Some($func));
-                state.set_field(-2, $field);
-                )*
-                state.push_fn(Some($ud::drop_it));
-                state.set_field(-2, "__gc");
-                state.pop(1); // pop metatable
-                if !created {
-                    panic!("Metatable '{}' already exists.", $ud::meta_name());
+/// Reads value at `index` decoded by `Codec` of type `T`.
+#[macro_export]
+macro_rules! read_codec {
+    ($state:ident, $index:expr, $t:ty) => {
+        <$t as $crate::Codec>::decode(&mut $state, $index)
+    };
+}
+
+/// Runs block with protected call, see `pcall_scope`. Returns `Result` with the value of block.
+///
+/// Inside the block `state` is `&mut State` of the protected call.
+#[macro_export]
+macro_rules! pcall_scope {
+    ($state:ident, $b:block) => {
+        $crate::pcall_scope(&mut $state, |$state: &mut $crate::lua::State| $b)
+    };
+}
+
+/// Makes `StackGuard` which restores top of the stack at the end of scope.
+#[macro_export]
+macro_rules! stack_guard {
+    ($state:ident) => {
+        $crate::StackGuard::new({
+            use $crate::AsStateMut;
+            $state.as_state_mut()
+        })
+    };
+}
+
+/// Declares Lua function which raises error "permission denied" unless `perm` is granted
+/// by `grant_permission`.
+///
+/// Parameters are arguments of the function converted by `convert_arguments`.
+#[macro_export]
+macro_rules! lua_guarded_fn {
+    (@check $state:ident, $perm:expr) => {
+        if !$crate::has_permission(&mut $state, $perm) {
+            $state.push_string(concat!("permission denied: ", $perm));
+            return $state.error();
+        }
+    };
+    (@args $state:ident, []) => {};
+    (@args $state:ident, [ $( $arg:ident : $aty:ty ),+ ]) => {
+        let ($( $arg, )+) = convert_arguments!($state, $( $aty ),+)
+            .unwrap_or_else(|pos| {
+                $state.arg_error(pos, "wrong argument");
+                unreachable!()
+            });
+    };
+    (perm = $perm:expr, fn $name:ident ($( $arg:ident : $aty:ty ),*) -> $ret:ty $body:block) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            lua_guarded_fn!(@check state, $perm);
+            lua_guarded_fn!(@args state, [ $( $arg : $aty ),* ]);
+            let result: $ret = $body;
+            state.push(result);
+            1
+        }
+    };
+    (perm = $perm:expr, fn $name:ident ($( $arg:ident : $aty:ty ),*) $body:block) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            lua_guarded_fn!(@check state, $perm);
+            lua_guarded_fn!(@args state, [ $( $arg : $aty ),* ]);
+            $body;
+            0
+        }
+    };
+}
+
+/// Declares Lua function with variants for different counts of arguments.
+///
+/// Every variant lists arguments converted by `convert_arguments` and an expression
+/// which result is pushed back. The variant is chosen by count of arguments only.
+#[macro_export]
+macro_rules! lua_overload_fn {
+    (@args $state:ident, []) => {};
+    (@args $state:ident, [ $( $arg:ident : $aty:ty ),+ ]) => {
+        let ($( $arg, )+) = match convert_arguments!($state, $( $aty ),+) {
+            Ok(args) => args,
+            Err(pos) => return $state.arg_error(pos, "wrong argument"),
+        };
+    };
+    (fn $name:ident { $( ( $( $arg:ident : $aty:ty ),* ) => $body:expr ),+ $(,)? }) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            let top = state.get_top();
+            $({
+                let names: &[&str] = &[$( stringify!($arg) ),*];
+                if top as usize == names.len() {
+                    lua_overload_fn!(@args state, [ $( $arg : $aty ),* ]);
+                    let result = $body;
+                    state.push(result);
+                    return 1;
                 }
-            }
+            })+
+            let arities: &[&[&str]] = &[$( &[$( stringify!($arg) ),*] ),+];
+            let arities: Vec<String> = arities.iter().map(|names| names.len().to_string()).collect();
+            let msg = format!("`{}` expects {} arguments, got {}", stringify!($name), arities.join(" or "), top);
+            drop(arities);
+            state.push_string(&msg);
+            drop(msg);
+            state.error()
+        }
+    };
+}
 
-            // TODO This is not tested, because right __gc signature not described in Lua doc
-            unsafe extern "C" fn drop_it(state: *mut $crate::lua::ffi::lua_State) -> i32 {
-                let mut state = $crate::lua::State::from_ptr(state);
-                if let Some(ptr) = state.to_userdata_typed::<$ud>(-1) {
-                    ::std::ptr::drop_in_place(ptr);
+/// Converts value at `index` to `T` once per object with `ConvertCache`.
+#[macro_export]
+macro_rules! memo_convert {
+    ($state:ident, $index:expr, $t:ty, $cache:expr) => {
+        $cache.convert(&mut $state, $index, |state, index| state.to_type::<$t>(index))
+    };
+}
+
+/// Declares Lua function which yields items of iterator returned by the body one by one.
+///
+/// Call it in a coroutine (`coroutine.wrap` makes a generator of it). Items are taken
+/// lazily, when the coroutine is resumed, so iterators may be infinite.
+#[macro_export]
+macro_rules! lua_generator_fn {
+    (@args $state:ident, [ ]) => {
+        ()
+    };
+    (@args $state:ident, [ $( $aty:ty ),+ ]) => {
+        convert_arguments!($state, $( $aty ),+)
+            .unwrap_or_else(|pos| {
+                $state.arg_error(pos, "wrong argument");
+                unreachable!()
+            })
+    };
+    (fn $name:ident ($( $arg:ident : $aty:ty ),*) -> $item:ty $body:block) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            unsafe extern "C" fn resume(ls: *mut $crate::lua::ffi::lua_State,
+                                        _status: $crate::lua::libc::c_int,
+                                        _ctx: $crate::lua::ffi::lua_KContext)
+                                        -> $crate::lua::libc::c_int {
+                // Yield jumps out of the function, so nothing has to be alive here
+                let more = {
+                    let mut state = $crate::lua::State::from_ptr(ls);
+                    $crate::generator_next::<$item>(&mut state, 1)
+                };
+                if more {
+                    $crate::lua::ffi::lua_yieldk(ls, 1, 0, Some(resume))
+                } else {
+                    0
                 }
-                0
             }
+            {
+                let mut state = $crate::lua::State::from_ptr(ls);
+                let ($( $arg, )*) = lua_generator_fn!(@args state, [ $( $aty ),* ]);
+                let iter: Box<dyn Iterator<Item = $item>> = Box::new(::std::iter::IntoIterator::into_iter($body));
+                state.set_top(0); // Iterator is kept at the bottom of the stack
+                $crate::push_generator(&mut state, iter);
+            }
+            resume(ls, 0, 0)
         }
+    };
+}
 
-        impl $crate::lua::FromLua for $ud {
-            fn from_lua(state: &mut $crate::lua::State, index: $crate::lua::Index) -> Option<Self> {
-                unsafe {
-                    state.test_userdata_typed::<$ud>(index, $ud::meta_name())
-                        .map(|p| p.clone())
+/// Adds `snapshot` and `restore` methods to userdata which implements `Clone`.
+///
+/// `obj:snapshot()` returns an opaque copy of the value and `obj:restore(snap)` puts
+/// the copy back. Call `attach_snapshots` after `attach`, handlers set before serve other keys.
+#[macro_export]
+macro_rules! lua_snapshottable {
+    ($ud:ident) => {
+        impl $ud {
+            /// Name of metatable of snapshots, it's derived from `meta_name`.
+            pub fn snapshot_meta_name() -> String {
+                format!("{}.Snapshot", $ud::meta_name())
+            }
+
+            pub fn attach_snapshots(state: &mut $crate::lua::State) {
+                unsafe extern "C" fn drop_snapshot(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+                    let mut state = $crate::lua::State::from_ptr(ls);
+                    let name = $ud::snapshot_meta_name();
+                    if let Some(ptr) = state.test_userdata_typed::<$ud>(1, &name) {
+                        ::std::ptr::drop_in_place(ptr);
+                        state.push_nil();
+                        state.set_metatable(1);
+                    }
+                    0
                 }
+                unsafe extern "C" fn snapshot(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+                    let mut state = $crate::lua::State::from_ptr(ls);
+                    let value = match state.test_userdata_typed::<$ud>(1, $ud::meta_name()) {
+                        Some(ptr) => ptr.clone(),
+                        None => return state.arg_error(1, concat!("`", stringify!($ud), "` expected")),
+                    };
+                    let name = $ud::snapshot_meta_name();
+                    let pointer = state.new_userdata_typed::<$ud>();
+                    ::std::ptr::write(pointer, value);
+                    state.set_metatable_from_registry(&name);
+                    1
+                }
+                unsafe extern "C" fn restore(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+                    let mut state = $crate::lua::State::from_ptr(ls);
+                    let name = $ud::snapshot_meta_name();
+                    let value = match state.test_userdata_typed::<$ud>(2, &name) {
+                        Some(ptr) => ptr.clone(),
+                        None => {
+                            drop(name);
+                            return state.arg_error(2, "snapshot expected");
+                        },
+                    };
+                    drop(name);
+                    match state.test_userdata_typed::<$ud>(1, $ud::meta_name()) {
+                        Some(this) => *this = value,
+                        None => {
+                            drop(value);
+                            return state.arg_error(1, concat!("`", stringify!($ud), "` expected"));
+                        },
+                    }
+                    0
+                }
+                unsafe extern "C" fn index(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+                    let mut state = $crate::lua::State::from_ptr(ls);
+                    if state.type_of(2) == Some($crate::lua::Type::String) {
+                        let method: $crate::lua::Function = match state.to_str(2) {
+                            Some("snapshot") => Some(snapshot),
+                            Some("restore") => Some(restore),
+                            _ => None,
+                        };
+                        if method.is_some() {
+                            state.push_fn(method);
+                            return 1;
+                        }
+                    }
+                    $crate::call_prev_index(&mut state, $crate::lua::ffi::lua_upvalueindex(1));
+                    1
+                }
+                let name = $ud::snapshot_meta_name();
+                if state.new_metatable(&name) {
+                    state.push_fn(Some(drop_snapshot));
+                    state.set_field(-2, "__gc");
+                }
+                state.pop(1);
+                // Previous handler becomes upvalue of new one
+                state.get_metatable_from_registry($ud::meta_name());
+                state.get_field(-1, "__index");
+                state.push_closure(Some(index), 1);
+                state.set_field(-2, "__index");
+                state.pop(1);
             }
         }
+    };
+}
 
-        impl $crate::lua::ToLua for $ud {
-            fn to_lua(&self, state: &mut $crate::lua::State) {
-                unsafe {
-                    let pointer = state.new_userdata_typed();
-                    let uninit = ::std::ptr::replace(pointer, self.clone());
-                    ::std::mem::forget(uninit);
-                }
-                state.set_metatable_from_registry($ud::meta_name());
+/// Declares Lua function `name(offset, limit)` which returns a page of `Vec<Item>` kept
+/// under `key` by `set_context` as an array.
+///
+/// Offset starts from 0. Only items of the page are cloned.
+#[macro_export]
+macro_rules! lua_paged_fn {
+    (fn $name:ident -> $item:ty, $key:expr) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            let (offset, limit) = match convert_arguments!(state, $crate::lua::Integer, $crate::lua::Integer) {
+                Ok(args) => args,
+                Err(pos) => return state.arg_error(pos, "integer expected"),
+            };
+            if offset < 0 {
+                return state.arg_error(1, "offset must not be negative");
+            }
+            if limit < 0 {
+                return state.arg_error(2, "limit must not be negative");
+            }
+            let page = $crate::with_context(&mut state, $key, |items: &mut Vec<$item>| {
+                let start = ::std::cmp::min(offset as usize, items.len());
+                let end = ::std::cmp::min(start.saturating_add(limit as usize), items.len());
+                items[start..end].to_vec()
+            });
+            match page {
+                Some(page) => {
+                    state.create_table(page.len() as $crate::lua::libc::c_int, 0);
+                    let mut idx = 0;
+                    for item in page {
+                        idx += 1; // Starts from 1 too
+                        state.push(item);
+                        state.raw_seti(-2, idx);
+                    }
+                    1
+                },
+                None => {
+                    state.push_string(concat!("data of `", stringify!($name), "` is not set"));
+                    state.error()
+                },
+            }
+        }
+    };
+}
+
+/// Declares Lua function with arguments converted by `convert_arguments` and the result
+/// pushed by `ToLua`.
+///
+/// A wrong argument raises error with expected type. Function without return type (or `-> ()`)
+/// returns nothing, tuple of types is returned as multiple values in order.
+#[macro_export]
+macro_rules! lua_function {
+    (@args $state:ident, []) => {};
+    (@args $state:ident, [ $( $arg:ident : $aty:ty ),+ ]) => {
+        let ($( $arg, )+) = match convert_arguments!($state, $( $aty ),+) {
+            Ok(args) => args,
+            Err(pos) => {
+                let expected: &[&'static str] = &[$( concat!(stringify!($aty), " expected") ),+];
+                let msg = expected.get(pos as usize - 1).cloned().unwrap_or("no value expected");
+                return $state.arg_error(pos, msg);
+            },
+        };
+    };
+    (fn $name:ident ($( $arg:ident : $aty:ty ),* $(,)?) -> () $body:block) => {
+        lua_function!(fn $name($( $arg : $aty ),*) $body);
+    };
+    (fn $name:ident ($( $arg:ident : $aty:ty ),* $(,)?) -> ( $first:ty, $( $rest:ty ),+ $(,)? ) $body:block) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            lua_function!(@args state, [ $( $arg : $aty ),* ]);
+            let results: ($first, $( $rest ),+) = $body;
+            $crate::LuaResults::push_results(results, &mut state)
+        }
+    };
+    (fn $name:ident ($( $arg:ident : $aty:ty ),* $(,)?) -> $ret:ty $body:block) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            lua_function!(@args state, [ $( $arg : $aty ),* ]);
+            let result: $ret = $body;
+            state.push(result);
+            1
+        }
+    };
+    (fn $name:ident ($( $arg:ident : $aty:ty ),* $(,)?) $body:block) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            #[allow(unused_mut, unused_variables)]
+            let mut state = $crate::lua::State::from_ptr(ls);
+            lua_function!(@args state, [ $( $arg : $aty ),* ]);
+            $body;
+            0
+        }
+    };
+}
+
+/// Declares Lua function which returns handle of future made by the body, see `push_async_handle`.
+///
+/// `T` is the output of the future. Parameters are converted like `lua_function` does.
+#[macro_export]
+macro_rules! lua_async_handle_fn {
+    (fn $name:ident ($( $arg:ident : $aty:ty ),* $(,)?) -> $ret:ty $body:block) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            lua_function!(@args state, [ $( $arg : $aty ),* ]);
+            $crate::push_async_handle::<$ret, _>(&mut state, $body);
+            1
+        }
+    };
+}
+
+/// Declares Lua function which validates values of its arguments before the body.
+///
+/// Every parameter is `name: kind(constraints)`:
+/// * `string(min_len = n, max_len = n)` - `String` with limits of length in chars (both optional).
+/// * `integer(range)` - `Integer` contained in the range, like `integer(0..=150)`.
+/// * `number(range)` - `Number` contained in the range.
+///
+/// Violated constraint raises argument error which names it. Parentheses are required,
+/// use `integer(..)` for any value.
+#[macro_export]
+macro_rules! lua_validated_fn {
+    (@string $pos:expr, $arg:ident, $value:ident, min_len = $n:expr) => {
+        if $value.chars().count() < $n {
+            return Err(($pos, concat!("`", stringify!($arg), "` violates min_len = ", stringify!($n))));
+        }
+    };
+    (@string $pos:expr, $arg:ident, $value:ident, max_len = $n:expr) => {
+        if $value.chars().count() > $n {
+            return Err(($pos, concat!("`", stringify!($arg), "` violates max_len = ", stringify!($n))));
+        }
+    };
+    (@type string) => { String };
+    (@type integer) => { $crate::lua::Integer };
+    (@type number) => { $crate::lua::Number };
+    (@read $state:ident, $pos:expr, $arg:ident, string ( $( $opt:ident = $n:expr ),* $(,)? )) => {{
+        let value = if $state.type_of($pos) == Some($crate::lua::Type::String) {
+            $state.to_type::<String>($pos)
+        } else {
+            None
+        };
+        let value = match value {
+            Some(value) => value,
+            None => return Err(($pos, concat!("`", stringify!($arg), "` must be string"))),
+        };
+        $(
+        lua_validated_fn!(@string $pos, $arg, value, $opt = $n);
+        )*
+        value
+    }};
+    (@read $state:ident, $pos:expr, $arg:ident, integer ( $range:expr )) => {{
+        // Numeric strings are rejected, as they are by strict `convert_arguments`
+        let value = if $state.type_of($pos) == Some($crate::lua::Type::Number) {
+            $state.to_type::<$crate::lua::Integer>($pos)
+        } else {
+            None
+        };
+        let value = match value {
+            Some(value) => value,
+            None => return Err(($pos, concat!("`", stringify!($arg), "` must be integer"))),
+        };
+        if !($range).contains(&value) {
+            return Err(($pos, concat!("`", stringify!($arg), "` violates range ", stringify!($range))));
+        }
+        value
+    }};
+    (@read $state:ident, $pos:expr, $arg:ident, number ( $range:expr )) => {{
+        let value = if $state.type_of($pos) == Some($crate::lua::Type::Number) {
+            $state.to_type::<$crate::lua::Number>($pos)
+        } else {
+            None
+        };
+        let value = match value {
+            Some(value) => value,
+            None => return Err(($pos, concat!("`", stringify!($arg), "` must be number"))),
+        };
+        if !($range).contains(&value) {
+            return Err(($pos, concat!("`", stringify!($arg), "` violates range ", stringify!($range))));
+        }
+        value
+    }};
+    (@args $state:ident, [ $( $arg:ident : $kind:ident $spec:tt ),* ]) => {
+        // Values are read first and the error is raised after they are dropped,
+        // because it doesn't run destructors
+        let read = |state: &mut $crate::lua::State|
+            -> Result<( $( lua_validated_fn!(@type $kind), )* ), ($crate::lua::Index, &'static str)>
+        {
+            #[allow(unused_mut)]
+            let mut position: $crate::lua::Index = 0;
+            $(
+            position += 1;
+            if position > state.get_top() {
+                return Err((position, concat!("`", stringify!($arg), "` expected")));
             }
+            let $arg = lua_validated_fn!(@read state, position, $arg, $kind $spec);
+            )*
+            if state.get_top() > position {
+                return Err((position + 1, "no value expected"));
+            }
+            Ok(( $( $arg, )* ))
+        };
+        let checked = read(&mut $state);
+        let ( $( $arg, )* ) = match checked {
+            Ok(values) => values,
+            Err((position, message)) => return $state.arg_error(position, message),
+        };
+    };
+    (fn $name:ident { $( $arg:ident : $kind:ident $spec:tt ),* $(,)? } -> $ret:ty $body:block) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            lua_validated_fn!(@args state, [ $( $arg : $kind $spec ),* ]);
+            let result: $ret = $body;
+            state.push(result);
+            1
+        }
+    };
+    (fn $name:ident { $( $arg:ident : $kind:ident $spec:tt ),* $(,)? } $body:block) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            let mut state = $crate::lua::State::from_ptr(ls);
+            lua_validated_fn!(@args state, [ $( $arg : $kind $spec ),* ]);
+            $body;
+            0
+        }
+    };
+}
+
+/// Calls global function `name` with arguments pushed by `ToLua` in protected mode.
+///
+/// Result is converted to `ret` (tuple of types for multiple results), error contains
+/// the message of Lua. The stack is restored in any case.
+#[macro_export]
+macro_rules! lua_call {
+    (@call $state:ident, $name:expr, ( $( $arg:expr ),* ), $nresults:expr, $read:expr) => {{
+        let top = $state.get_top();
+        $state.get_global($name);
+        $(
+        $state.push($arg);
+        )*
+        let nargs = $state.get_top() - top - 1;
+        let result = match $state.pcall(nargs, $nresults, 0) {
+            $crate::lua::ThreadStatus::Ok => $read,
+            _ => Err($state.to_type::<String>(-1)
+                .unwrap_or_else(|| format!("unknown error of `{}`", $name))),
+        };
+        $state.set_top(top);
+        result
+    }};
+    ($state:ident, $name:expr, ( $( $arg:expr ),* $(,)? ) -> ( $first:ty, $( $rest:ty ),+ $(,)? )) => {{
+        let names: &[&str] = &[stringify!($first), $( stringify!($rest) ),+];
+        lua_call!(@call $state, $name, ( $( $arg ),* ), names.len() as $crate::lua::libc::c_int, {
+            convert_arguments!(@strict false, $state, $first, $( $rest ),+)
+                .map_err(|pos| format!("unexpected result #{} of `{}`", pos, $name))
+        })
+    }};
+    ($state:ident, $name:expr, ( $( $arg:expr ),* $(,)? ) -> $ret:ty) => {
+        lua_call!(@call $state, $name, ( $( $arg ),* ), 1, {
+            $state.to_type::<$ret>(-1)
+                .ok_or_else(|| format!("unexpected result of `{}`", $name))
+        })
+    };
+}
+
+/// Declares Lua function which caches results by values of arguments.
+///
+/// Cache is kept in the registry by `set_context`. Types of arguments have to implement
+/// `Clone`, `Eq` and `Hash`, type of result has to implement `Clone`.
+#[macro_export]
+macro_rules! lua_memoized_fn {
+    (fn $name:ident ($( $arg:ident : $aty:ty ),* $(,)?) -> $ret:ty $body:block) => {
+        unsafe extern "C" fn $name(ls: *mut $crate::lua::ffi::lua_State) -> $crate::lua::libc::c_int {
+            type Cache = ::std::collections::HashMap<($( $aty, )*), $ret>;
+            let mut state = $crate::lua::State::from_ptr(ls);
+            lua_function!(@args state, [ $( $arg : $aty ),* ]);
+            let context = concat!("lua_macros.memo.", module_path!(), "::", stringify!($name));
+            let key = ($( $arg.clone(), )*);
+            let cached = $crate::with_context(&mut state, context, |cache: &mut Cache| cache.get(&key).cloned());
+            let result: $ret = match cached {
+                Some(Some(result)) => result,
+                cached => {
+                    if cached.is_none() {
+                        $crate::set_context(&mut state, context, Cache::new());
+                    }
+                    let result: $ret = $body;
+                    let value = result.clone();
+                    $crate::with_context(&mut state, context, move |cache: &mut Cache| cache.insert(key, value));
+                    result
+                },
+            };
+            state.push(result);
+            1
         }
     };
 }
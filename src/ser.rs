@@ -0,0 +1,357 @@
+//! Serialization of Rust's types to Lua's values with serde.
+
+use std::error;
+use std::fmt;
+
+use lua::{State, Integer};
+use lua::ffi::lua_pushlstring;
+use lua::libc::c_int;
+use serde::ser::{self, Serialize, SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant,
+                 SerializeTuple, SerializeTupleStruct, SerializeTupleVariant};
+
+/// Error of serialization.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+/// Pushes `value` to the stack. Nothing is pushed on error.
+pub fn to_lua<T: Serialize + ?Sized>(state: &mut State, value: &T) -> Result<(), Error> {
+    let top = state.get_top();
+    let result = value.serialize(&mut Serializer::new(state));
+    if result.is_err() {
+        state.set_top(top);
+    }
+    result
+}
+
+/// Serializer which pushes every value to the stack.
+///
+/// Structs and maps become tables, sequences and tuples become arrays,
+/// `None` and unit become `nil`. Unit variants of enums become strings,
+/// other variants become tables with single field `variant = value`.
+pub struct Serializer<'a> {
+    state: &'a mut State,
+}
+
+impl<'a> Serializer<'a> {
+    pub fn new(state: &'a mut State) -> Self {
+        Serializer {
+            state: state,
+        }
+    }
+}
+
+fn size(len: Option<usize>) -> c_int {
+    len.unwrap_or(0) as c_int
+}
+
+impl<'a, 'b> ser::Serializer for &'b mut Serializer<'a> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'b, 'a>;
+    type SerializeTuple = Compound<'b, 'a>;
+    type SerializeTupleStruct = Compound<'b, 'a>;
+    type SerializeTupleVariant = Compound<'b, 'a>;
+    type SerializeMap = Compound<'b, 'a>;
+    type SerializeStruct = Compound<'b, 'a>;
+    type SerializeStructVariant = Compound<'b, 'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.state.push_bool(v);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.state.push_integer(v as Integer);
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        if v > Integer::max_value() as u64 {
+            // Too big for integers of Lua
+            self.serialize_f64(v as f64)
+        } else {
+            self.serialize_i64(v as i64)
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.state.push_number(v);
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.state.push_string(&v.to_string());
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.state.push_string(v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        // Strings of Lua are byte strings
+        unsafe {
+            lua_pushlstring(self.state.as_ptr(), v.as_ptr() as *const _, v.len());
+        }
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.state.push_nil();
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str)
+        -> Result<(), Error>
+    {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T)
+        -> Result<(), Error>
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32,
+                                                        variant: &'static str, value: &T)
+        -> Result<(), Error>
+    {
+        self.state.create_table(0, 1);
+        value.serialize(&mut *self)?;
+        self.state.set_field(-2, variant);
+        Ok(())
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Compound<'b, 'a>, Error> {
+        self.state.create_table(size(len), 0);
+        Ok(Compound::new(self, None))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'b, 'a>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Compound<'b, 'a>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, variant: &'static str, len: usize)
+        -> Result<Compound<'b, 'a>, Error>
+    {
+        self.state.create_table(0, 1);
+        self.state.create_table(len as c_int, 0);
+        Ok(Compound::new(self, Some(variant)))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Compound<'b, 'a>, Error> {
+        self.state.create_table(0, size(len));
+        Ok(Compound::new(self, None))
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Compound<'b, 'a>, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, variant: &'static str, len: usize)
+        -> Result<Compound<'b, 'a>, Error>
+    {
+        self.state.create_table(0, 1);
+        self.state.create_table(0, len as c_int);
+        Ok(Compound::new(self, Some(variant)))
+    }
+}
+
+/// Table on top of the stack which is filled with items or fields.
+///
+/// Tables of variants are nested in a table with single field named by `variant`.
+pub struct Compound<'b, 'a: 'b> {
+    ser: &'b mut Serializer<'a>,
+    variant: Option<&'static str>,
+    len: Integer,
+}
+
+impl<'b, 'a> Compound<'b, 'a> {
+    fn new(ser: &'b mut Serializer<'a>, variant: Option<&'static str>) -> Self {
+        Compound {
+            ser: ser,
+            variant: variant,
+            len: 0,
+        }
+    }
+
+    fn item<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)?;
+        self.len += 1; // Starts from 1 too
+        self.ser.state.raw_seti(-2, self.len);
+        Ok(())
+    }
+
+    fn field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)?;
+        self.ser.state.set_field(-2, key);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        if let Some(variant) = self.variant {
+            self.ser.state.set_field(-2, variant);
+        }
+        Ok(())
+    }
+}
+
+impl<'b, 'a> SerializeSeq for Compound<'b, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'b, 'a> SerializeTuple for Compound<'b, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'b, 'a> SerializeTupleStruct for Compound<'b, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'b, 'a> SerializeTupleVariant for Compound<'b, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'b, 'a> SerializeMap for Compound<'b, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        key.serialize(&mut *self.ser)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)?;
+        if self.ser.state.is_nil(-2) {
+            return Err(Error("key of map is nil".to_owned()));
+        }
+        self.ser.state.raw_set(-3);
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'b, 'a> SerializeStruct for Compound<'b, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'b, 'a> SerializeStructVariant for Compound<'b, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}